@@ -0,0 +1,59 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use toktokenizer::tokenizer::{BasicTokenizer, Tokenize};
+
+fn trained_tokenizer() -> BasicTokenizer {
+    let mut text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+    text.push_str(&" ".repeat(256));
+    text.push_str(&"a".repeat(256));
+    let mut tokenizer = BasicTokenizer::new();
+    tokenizer.train(&text, 500, false);
+    tokenizer
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let tokenizer = trained_tokenizer();
+    let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+    let long_run = "a".repeat(100_000);
+    let indentation = format!("{}x\n", " ".repeat(64)).repeat(1_000);
+
+    c.bench_function("encode train.txt", |b| b.iter(|| tokenizer.encode(black_box(&text))));
+    c.bench_function("encode_flat train.txt", |b| b.iter(|| tokenizer.encode_flat(black_box(&text))));
+    c.bench_function("encode long run", |b| b.iter(|| tokenizer.encode(black_box(&long_run))));
+    c.bench_function("encode indentation", |b| b.iter(|| tokenizer.encode(black_box(&indentation))));
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let tokenizer = trained_tokenizer();
+    let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+    let ids = tokenizer.encode(&text.repeat(20));
+
+    c.bench_function("decode long id stream", |b| b.iter(|| tokenizer.decode(black_box(&ids))));
+}
+
+// The vocab part of `save` built with one `format!` per entry, as it used to be
+fn format_save_vocab(tokenizer: &BasicTokenizer, vocab: &mut Vec<u8>) {
+    for id in tokenizer.contiguous_ids() {
+        let token = tokenizer.token(id).unwrap();
+        vocab.extend_from_slice(format!("[{}] -> ({})\n", id, String::from_utf8_lossy(token.bytes)).as_bytes());
+    }
+}
+
+// Only the vocab is compared, merges carry no byte payload
+fn bench_save(c: &mut Criterion) {
+    let tokenizer = trained_tokenizer();
+
+    c.bench_function("save vocab format!", |b| b.iter(|| {
+        let mut vocab = Vec::new();
+        format_save_vocab(black_box(&tokenizer), &mut vocab);
+        vocab
+    }));
+    c.bench_function("save_to vocab", |b| b.iter(|| {
+        let mut vocab = Vec::new();
+        black_box(&tokenizer).save_to(&mut vocab, &mut std::io::sink()).unwrap();
+        vocab
+    }));
+}
+
+criterion_group!(benches, bench_encode, bench_decode, bench_save);
+criterion_main!(benches);
@@ -1,9 +1,54 @@
+use std::process::ExitCode;
 use toktokenizer::tokenizer::{BasicTokenizer, Tokenize};
 
-fn main() {
+const USAGE: &str = "usage: toktokenizer [encode <merges.txt> <text> | decode <merges.txt> <id>...]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        None => train(),
+        Some("encode") if args.len() == 3 => load(&args[1]).map(|tknzr| {
+            let ids: Vec<String> = tknzr.encode(&args[2]).iter().map(|id| id.to_string()).collect();
+            println!("{}", ids.join(" "));
+        }),
+        Some("decode") if args.len() >= 2 => load(&args[1]).and_then(|tknzr| {
+            let ids = args[2..].iter()
+                .map(|id| id.parse().map_err(|_| format!("`{id}` is not a token id")))
+                .collect::<Result<Vec<u32>, _>>()?;
+            let text = tknzr.decode(&ids).map_err(|e| format!("could not decode: {e}"))?;
+            println!("{text}");
+            Ok(())
+        }),
+        Some(_) => Err(USAGE.to_string())
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(msg) => {
+            eprintln!("error: {msg}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn train() -> Result<(), String> {
     let mut tknzr = BasicTokenizer::new();
-    let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+    let text = std::fs::read_to_string("train.txt").map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => "training file `train.txt` not found. Run `toktokenizer` from a directory containing it".to_string(),
+        _ => format!("could not read training file `train.txt`: {e}")
+    })?;
     tknzr.train(&text, 500, true);
-    tknzr.save("vocab.model", "merges.txt").expect("Could not save tokenizer");
+    tknzr.save("vocab.model", "merges.txt").map_err(|e| format!("could not save the model to `vocab.model` and `merges.txt`: {e}"))?;
+    Ok(())
 }
 
+// Turns io errors into a message naming the model file and how to get one
+fn load(merges_path: &str) -> Result<BasicTokenizer, String> {
+    BasicTokenizer::load(merges_path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => format!(
+            "model file `{merges_path}` not found. Run `toktokenizer` without arguments to train one (it writes merges.txt), or pass the path of an existing merges file"
+        ),
+        std::io::ErrorKind::InvalidData => format!("model file `{merges_path}` is not a valid merges file: {e}"),
+        _ => format!("could not read model file `{merges_path}`: {e}")
+    })
+}
@@ -1,221 +1,4360 @@
-use std::{collections::HashMap};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
 use bytes::{Bytes, BytesMut};
-use regex::Regex;
+use rayon::prelude::*;
+use fancy_regex::Regex;
 use std::io::{BufWriter, Write};
 
 
 pub trait Tokenize {
     fn train(&mut self, text: &str, vocab_size: usize, verbose: bool);
     fn encode(&self, text: &str) -> Vec<u32>;
-    fn decode(&self, ids: &[u32]) -> String;
+    fn decode(&self, ids: &[u32]) -> Result<String, DecodeError>;
     fn save(&self, vocab_path: &str, merges_path: &str) -> std::io::Result<()>;
+    /// Tokens in the vocab, counted like the `vocab_size` of `train`: special tokens are not included.
+    fn vocab_size(&self) -> usize;
 }
 
-pub struct BasicTokenizer {
-    vocab: HashMap<u32, Bytes>,
-    merges: HashMap<(u32, u32), u32>
+type Stats = HashMap<(u32, u32), u32>;
+type Normalizer = dyn for<'a> Fn(&'a str) -> Cow<'a, str> + Send + Sync;
+
+// Unit of normalized text encoding works on
+enum Segment<'a> {
+    // Text the merges are confined to, spelled with the word marker if there is one
+    Piece(Cow<'a, str>),
+    // Registered special token found in the text
+    Special(u32),
 }
 
-impl BasicTokenizer {
-    pub fn new() -> Self {
-        BasicTokenizer { vocab: HashMap::new(), merges: HashMap::new() }
+/// The units the tokenizer starts merging from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alphabet {
+    /// The 256 byte values, every input can be represented.
+    Bytes,
+    /// The 256 byte values plus one token for each multi-byte char seen during training.
+    Chars,
+}
+
+/// What `encode` does with a char that is not part of a char-level alphabet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownPolicy {
+    /// Fail with `EncodeError::UnknownChar`.
+    Error,
+    /// Replace the char with the given id.
+    Unk(u32),
+    /// Split the char into its UTF-8 bytes, which are always part of the base alphabet.
+    ByteFallback,
+}
+
+/// What `StreamDecoder::finish` does with bytes left over from an id stream cut in the middle of a char.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingBytes {
+    /// Fail with `DecodeError::InvalidUtf8`.
+    Error,
+    /// Emit a single `U+FFFD` replacement char.
+    Replace,
+    /// Hand the bytes back to the caller, e.g. to prepend them to the next stream.
+    Return,
+}
+
+/// Whether `encode` turns registered special token strings found in the text into their ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialMatching {
+    /// Special strings are encoded like any other text.
+    Off,
+    /// Only the exact registered string is recognized.
+    Exact,
+    /// Matches regardless of ASCII case, e.g. `<|ENDOFTEXT|>` for `<|endoftext|>`. `decode` always gives
+    /// back the registered casing. If two specials differ only by case, both match the same text and the
+    /// one with the lowest id wins.
+    IgnoreAsciiCase,
+}
+
+/// Size of each id in the binary files of `encode_to_file` and `read_ids`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdWidth {
+    U16,
+    U32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    UnknownChar(char),
+    /// The id does not fit in the requested output width, in bits.
+    IdTooLarge { id: u32, bits: u32 },
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::UnknownChar(c) => write!(f, "char {c:?} is not in the alphabet"),
+            EncodeError::IdTooLarge { id, bits } => write!(f, "id {id} does not fit in {bits} bits"),
+        }
     }
 }
 
-fn merge(ids: &[u32], pair: (u32, u32), idx: u32) -> Vec<u32> {
-    let mut new_ids: Vec<u32> = Vec::with_capacity(ids.len());
-    
-    let mut merged = false;
-    // Sliding window of size 2 to get all bigrams
-    for window in ids.windows(2) {
-        if merged { 
-            merged = false; 
-            continue
+impl std::error::Error for EncodeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenizerError {
+    /// The vocab cannot be smaller than the 256 base bytes.
+    VocabSizeTooSmall { vocab_size: usize, min: usize },
+    /// Training was estimated to need more memory than the configured limit.
+    MemoryLimitExceeded { projected: usize, limit: usize },
+}
+
+impl std::fmt::Display for TokenizerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenizerError::VocabSizeTooSmall { vocab_size, min } => {
+                write!(f, "vocab size {vocab_size} is smaller than the base alphabet ({min})")
+            }
+            TokenizerError::MemoryLimitExceeded { projected, limit } => {
+                write!(f, "training is projected to use {projected} bytes, over the limit of {limit} bytes")
+            }
         }
+    }
+}
 
-        let (b1, b2) = (window[0], window[1]);
-        if pair.0 == b1 && pair.1 == b2 {
-            new_ids.push(idx);
-            merged = true
+impl std::error::Error for TokenizerError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The id is neither in the vocab nor a registered special token.
+    UnknownId(u32),
+    /// The decoded bytes are not valid UTF-8.
+    InvalidUtf8(std::string::FromUtf8Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnknownId(id) => write!(f, "unknown token id {id}"),
+            DecodeError::InvalidUtf8(e) => write!(f, "decoded bytes are not valid UTF-8: {e}"),
         }
-        else {
-            new_ids.push(b1);
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A problem found by `BasicTokenizer::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModelIssue {
+    /// The minted id is not greater than both halves, which training never produces.
+    /// A merge using its own id as a half is one of these and would never stop expanding.
+    NonMonotonicMerge { pair: (u32, u32), minted: u32 },
+}
+
+impl std::fmt::Display for ModelIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelIssue::NonMonotonicMerge { pair: (a, b), minted } => {
+                write!(f, "merge [{a}][{b}] -> [{minted}] mints an id not greater than its halves")
+            }
         }
     }
+}
 
-    new_ids.push(ids[ids.len() - 1]);
-    new_ids
+#[derive(Debug)]
+pub enum PatternError {
+    /// The pattern is not a valid regex.
+    Regex(fancy_regex::Error),
+    /// The pattern matches the empty string, so it would split the text into empty chunks.
+    MatchesEmpty,
 }
 
-fn get_stats(ids: &[u32], stats: &mut HashMap<(u32, u32), u32>) {
-    for window in ids.windows(2) {
-        *stats.entry((window[0], window[1])).or_insert(0) += 1;
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatternError::Regex(e) => write!(f, "invalid split pattern: {e}"),
+            PatternError::MatchesEmpty => write!(f, "split pattern matches the empty string"),
+        }
     }
 }
 
-impl Tokenize for BasicTokenizer {
-    fn train(&mut self, text: &str, vocab_size: usize, verbose: bool) {
-        let num_merges: usize = vocab_size - 256;
-        let mut ids: Vec<u32> = text.as_bytes().iter().map(|&b| b as u32).collect();
-        
-        // Initialize vocab with single value bytes
-        for i in 0..256 {
-            self.vocab.insert(i, Bytes::copy_from_slice(&[i as u8]));
+impl std::error::Error for PatternError {}
+
+impl From<fancy_regex::Error> for PatternError {
+    fn from(e: fancy_regex::Error) -> Self {
+        PatternError::Regex(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum ChainError {
+    /// A chain needs at least one tokenizer, the last one handles every chunk the others leave.
+    Empty,
+    Pattern(PatternError),
+}
+
+impl std::fmt::Display for ChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainError::Empty => write!(f, "tokenizer chain is empty"),
+            ChainError::Pattern(e) => e.fmt(f),
         }
+    }
+}
 
+impl std::error::Error for ChainError {}
 
-        for i in 0..num_merges {
-            // Update the bigrams counts hashmap
-            let mut stats = HashMap::new();
-            get_stats(&ids, &mut stats);
-            
-            // Take the bigram that occuress more oftent
-            let pair = stats.iter().max_by_key(|(_, v)| *v).map(|(k, _)| *k).unwrap();
-            
-            let minted_idx = self.vocab.len() as u32;
-            ids = merge(&ids, pair, minted_idx);
+impl From<PatternError> for ChainError {
+    fn from(e: PatternError) -> Self {
+        ChainError::Pattern(e)
+    }
+}
 
-            // Concatenate bytes pair
-            let (b1, b2) = (self.vocab.get(&pair.0).unwrap(), self.vocab.get(&pair.1).unwrap());
-            let mut buf: BytesMut = bytes::BytesMut::with_capacity(b1.len() + b2.len());
-            buf.extend_from_slice(b1.as_ref());
-            buf.extend_from_slice(b2.as_ref());
-            let concat_bytes: Bytes = buf.freeze();
+// Merges between two full recounts checking the incremental stats of training, in debug builds only
+const STATS_CHECK_INTERVAL: usize = 8;
 
-            self.vocab.insert(minted_idx, concat_bytes);
-            self.merges.insert(pair, minted_idx);
+// Rough per entry costs used to estimate memory usage
+const VOCAB_ENTRY_SIZE: usize = std::mem::size_of::<(u32, Bytes)>();
+const MERGE_ENTRY_SIZE: usize = std::mem::size_of::<((u32, u32), u32)>();
+const PROJECTED_TOKEN_LEN: usize = 8;
 
-            if verbose {
-                let minted_token = str::from_utf8(self.vocab.get(&minted_idx).unwrap().as_ref()).unwrap();
-                let percentage = (i + 1) as f64 / num_merges as f64 * 100.0;
-                println!("{}/{} - {:.2}%", i+1, num_merges, percentage);
-                println!("Merged [{}] [{}] -> [{}] ({})",  pair.0, pair.1, minted_idx, minted_token);
-            }
+#[derive(Clone)]
+pub struct BasicTokenizer {
+    vocab: HashMap<u32, Bytes>,
+    merges: HashMap<(u32, u32), u32>,
+    // Same content as `merges`, sorted by pair for cache friendly binary search lookups
+    flat_merges: Vec<((u32, u32), u32)>,
+    alphabet: Alphabet,
+    char_ids: HashMap<char, u32>,
+    unknown: UnknownPolicy,
+    word_marker: Option<char>,
+    normalize_newlines: bool,
+    normalizer: Option<std::sync::Arc<Normalizer>>,
+    bos: Option<u32>,
+    eos: Option<u32>,
+    progress_format: ProgressFormat,
+    timed_progress: bool,
+    priority_pairs: Vec<(u32, u32)>,
+    special_tokens: HashMap<String, u32>,
+    inverse_special_tokens: HashMap<u32, String>,
+    special_matching: SpecialMatching,
+    // Set by `with_grapheme_warnings`, filled by training
+    grapheme_warnings: Option<Vec<u32>>,
+    // Bytes to id, built on first use or read by `load_with_reverse_index`, reset whenever the vocab changes
+    reverse_vocab: std::sync::OnceLock<HashMap<Bytes, u32>>
+}
+
+impl BasicTokenizer {
+    pub fn new() -> Self {
+        BasicTokenizer {
+            vocab: HashMap::new(),
+            merges: HashMap::new(),
+            flat_merges: Vec::new(),
+            alphabet: Alphabet::Bytes,
+            char_ids: HashMap::new(),
+            unknown: UnknownPolicy::ByteFallback,
+            word_marker: None,
+            normalize_newlines: false,
+            normalizer: None,
+            bos: None,
+            eos: None,
+            progress_format: ProgressFormat::default(),
+            timed_progress: false,
+            priority_pairs: Vec::new(),
+            special_tokens: HashMap::new(),
+            inverse_special_tokens: HashMap::new(),
+            special_matching: SpecialMatching::Off,
+            grapheme_warnings: None,
+            reverse_vocab: std::sync::OnceLock::new()
         }
     }
 
-    fn encode(&self, text: &str) -> Vec<u32> {
-        // Converting the text bytes to integers
-        let mut ids: Vec<u32> = text.as_bytes().iter().map(|&b| b as u32).collect();
-        while ids.len() > 1 {
-            let bigrams: Vec<&[u32]> = ids.windows(2).collect();
-            
-            // Get the merged pair with the lowest idx (which is a reference to a reference to a slice)
-            let pair = bigrams.iter().min_by_key(
-                |&&bigram| self.merges.get(&(bigram[0], bigram[1])).unwrap_or(&u32::MAX)
-            );
+    /// Builds a tokenizer and trains it on `text` in one go.
+    pub fn trained(text: &str, vocab_size: usize) -> Result<Self, TokenizerError> {
+        let mut tokenizer = Self::new();
+        tokenizer.try_train(text, vocab_size, None, false)?;
+        Ok(tokenizer)
+    }
 
-            match pair {
-                Some(&pair) => {
-                    let (idx1, idx2) = (pair[0], pair[1]);
-                    if !self.merges.contains_key(&(idx1, idx2)) { break }
-                    ids = merge(&ids, (idx1, idx2), self.merges[&(idx1, idx2)]);
-                }
-                None => { break }
+    /// Checked version of `train`: the vocab size is validated and, when a `memory_limit` in bytes is given,
+    /// the projected footprint is compared against it before any work is done. No other training method
+    /// takes a memory cap.
+    pub fn try_train(&mut self, text: &str, vocab_size: usize, memory_limit: Option<usize>, verbose: bool) -> Result<(), TokenizerError> {
+        if vocab_size < 256 {
+            return Err(TokenizerError::VocabSizeTooSmall { vocab_size, min: 256 })
+        }
+        if let Some(limit) = memory_limit {
+            let projected = Self::projected_footprint(text.len(), vocab_size);
+            if projected > limit {
+                return Err(TokenizerError::MemoryLimitExceeded { projected, limit })
             }
         }
-        ids
+        self.train(text, vocab_size, verbose);
+        Ok(())
+    }
+
+    /// Registers special tokens such as `<|endoftext|>` with their ids.
+    /// The ids should not collide with the trained vocab.
+    pub fn register_special_tokens(&mut self, tokens: &[(&str, u32)]) {
+        for &(token, id) in tokens {
+            self.special_tokens.insert(token.to_string(), id);
+            self.inverse_special_tokens.insert(id, token.to_string());
+        }
+    }
+
+    /// Same as `decode`, but registered special tokens are left out of the output.
+    pub fn decode_skip_special(&self, ids: &[u32]) -> Result<String, DecodeError> {
+        self.decode_with(ids, true)
+    }
+
+    /// Decodes each run of ids between two `boundary` ids on its own, e.g. to get back the turns of a chat.
+    /// Like `str::split`, leading, trailing and consecutive boundaries give empty segments, which are kept
+    /// so that segment `i` always follows the `i`-th boundary. The boundary ids themselves are dropped.
+    pub fn decode_segments(&self, ids: &[u32], boundary: u32) -> Result<Vec<String>, DecodeError> {
+        ids.split(|&id| id == boundary).map(|segment| self.decode_with(segment, false)).collect()
+    }
+
+    /// Decoder fed one id at a time, which gives back text as soon as the bytes form complete chars.
+    pub fn stream_decoder(&self, trailing: TrailingBytes) -> StreamDecoder<'_> {
+        StreamDecoder { tokenizer: self, pending: Vec::new(), trailing }
+    }
+
+    // Bytes of an id, special tokens take precedence over the vocab
+    fn token_bytes(&self, idx: &u32) -> Result<&[u8], DecodeError> {
+        match self.inverse_special_tokens.get(idx) {
+            Some(special) => Ok(special.as_bytes()),
+            None => self.vocab.get(idx).map(|token| token.as_ref()).ok_or(DecodeError::UnknownId(*idx))
+        }
     }
 
-    fn decode(&self, ids: &[u32]) -> String {
-        let mut buf = BytesMut::new();
+    fn decode_with(&self, ids: &[u32], skip_special: bool) -> Result<String, DecodeError> {
+        // Sizing the buffer up front means a single allocation, however long the id stream.
+        // Skipped special tokens are counted too, which only overestimates
+        let mut len = 0;
+        for idx in ids {
+            len += self.token_bytes(idx)?.len();
+        }
+        let mut buf = Vec::with_capacity(len);
         for idx in ids {
-            buf.extend_from_slice(self.vocab[idx].as_ref());
+            if skip_special && self.inverse_special_tokens.contains_key(idx) {
+                continue
+            }
+            buf.extend_from_slice(self.token_bytes(idx)?);
         }
-        String::from_utf8(buf.freeze().to_vec()).unwrap()
+        let text = String::from_utf8(buf).map_err(DecodeError::InvalidUtf8)?;
+        Ok(self.unmark(text))
     }
 
-    fn save(&self, vocab_path: &str, merges_path: &str) -> std::io::Result<()> {
-        let file = std::fs::File::create(vocab_path)?;
-        let mut writer = BufWriter::new(file);
+    fn unmark(&self, text: String) -> String {
+        match self.word_marker {
+            Some(marker) => text.replace(marker, " "),
+            None => text
+        }
+    }
+
+    /// Releases the spare capacity of the internal maps, called automatically at the end of training.
+    /// Token bytes are allocated with their exact size so they need no compaction.
+    pub fn shrink_to_fit(&mut self) {
+        self.vocab.shrink_to_fit();
+        self.merges.shrink_to_fit();
+        self.flat_merges.shrink_to_fit();
+        self.char_ids.shrink_to_fit();
+        self.special_tokens.shrink_to_fit();
+        self.inverse_special_tokens.shrink_to_fit();
+    }
+
+    /// Approximate number of bytes held by the vocab and merges maps.
+    pub fn memory_footprint(&self) -> usize {
+        let token_bytes: usize = self.vocab.values().map(|bytes| bytes.len()).sum();
+        self.vocab.capacity() * VOCAB_ENTRY_SIZE + token_bytes + self.merges.capacity() * MERGE_ENTRY_SIZE
+    }
+
+    /// Approximate peak memory of a training run: the model itself plus the working id sequences
+    /// (the current one and the one being merged into). Token lengths are unknown beforehand,
+    /// so an average of `PROJECTED_TOKEN_LEN` bytes is assumed.
+    pub fn projected_footprint(text_len: usize, vocab_size: usize) -> usize {
+        let model = vocab_size * (VOCAB_ENTRY_SIZE + PROJECTED_TOKEN_LEN)
+            + vocab_size.saturating_sub(256) * MERGE_ENTRY_SIZE;
+        model + 2 * text_len * std::mem::size_of::<u32>()
+    }
+
+    pub fn with_alphabet(mut self, alphabet: Alphabet) -> Self {
+        self.alphabet = alphabet;
+        self
+    }
+
+    pub fn with_unknown_policy(mut self, policy: UnknownPolicy) -> Self {
+        self.unknown = policy;
+        self
+    }
+
+    /// SentencePiece style word boundaries: spaces are replaced by `marker` (e.g. `'▁'`) and merges never
+    /// cross a marker, so every token either starts a word (and carries the marker) or continues one.
+    /// `decode` turns the markers back into spaces, so text that already contains `marker` does not round-trip.
+    pub fn with_word_marker(mut self, marker: char) -> Self {
+        self.word_marker = Some(marker);
+        self
+    }
+
+    /// How `encode` and the other encode methods recognize registered special tokens in the normalized text,
+    /// `SpecialMatching::Off` by default. The longest match wins, and the text around a special is encoded
+    /// as if the special was not there. Training does not look for specials.
+    pub fn with_special_matching(mut self, matching: SpecialMatching) -> Self {
+        self.special_matching = matching;
+        self
+    }
+
+    /// Checks the trained model against its corpus at the end of training, see `grapheme_warnings` for the result.
+    /// Advisory only, tokens that split grapheme clusters are kept.
+    pub fn with_grapheme_warnings(mut self) -> Self {
+        self.grapheme_warnings = Some(Vec::new());
+        self
+    }
+
+    /// With `with_grapheme_warnings`, the tokens of `grapheme_splitting_tokens` on the corpus of the last
+    /// training (`train`, `continue_training` and their variants). `None` when the check is off.
+    pub fn grapheme_warnings(&self) -> Option<&[u32]> {
+        self.grapheme_warnings.as_deref()
+    }
+
+    /// How merges are printed when training with `verbose`.
+    pub fn with_progress_format(mut self, format: ProgressFormat) -> Self {
+        self.progress_format = format;
+        self
+    }
+
+    /// Times the training so that `TrainProgress::eta` is filled in (and printed when `verbose`).
+    /// Off by default, which saves reading the clock after every merge.
+    pub fn with_timed_progress(mut self) -> Self {
+        self.timed_progress = true;
+        self
+    }
+
+    /// Pairs minted first, in the given order, before training falls back to picking the most frequent pair.
+    /// They take the lowest merge ranks and count toward the vocab size, so every learned merge is shifted
+    /// by their number. A pair can use the id minted by an earlier one; pairs whose ids do not exist are ignored.
+    pub fn prioritize_pairs(&mut self, pairs: &[(u32, u32)]) {
+        self.priority_pairs.extend_from_slice(pairs);
+    }
+
+    /// Makes `encode` start every sequence with `id`, usually a registered special token.
+    /// It is part of the returned ids, so it counts toward any length limit applied to them.
+    pub fn with_bos(mut self, id: u32) -> Self {
+        self.bos = Some(id);
+        self
+    }
+
+    /// Makes `encode` end every sequence with `id`, see `with_bos`.
+    pub fn with_eos(mut self, id: u32) -> Self {
+        self.eos = Some(id);
+        self
+    }
+
+    /// Turns `\r\n` and lone `\r` into `\n` before training and encoding, so Windows and Unix files give
+    /// the same ids. This is lossy: `decode` gives back `\n` newlines only.
+    pub fn with_normalized_newlines(mut self) -> Self {
+        self.normalize_newlines = true;
+        self
+    }
+
+    /// Custom preprocessing (stripping markup, collapsing whitespace...) run on the text before anything else,
+    /// during both training and encoding so they always agree. `decode` cannot undo it: it returns the normalized text.
+    pub fn with_normalizer(mut self, normalizer: impl Fn(&str) -> Cow<str> + Send + Sync + 'static) -> Self {
+        self.normalizer = Some(std::sync::Arc::new(normalizer));
+        self
+    }
+
+    /// Like `encode`, but reports chars outside of the alphabet when the policy is `UnknownPolicy::Error`.
+    pub fn try_encode(&self, text: &str) -> Result<Vec<u32>, EncodeError> {
+        self.encode_up_to(text, u32::MAX)
+    }
+
+    /// One byte per id for tiny models (vocab of at most 256 tokens, BOS/EOS and special tokens included),
+    /// e.g. for embedded targets. Fails on the first id above 255.
+    pub fn encode_u8(&self, text: &str) -> Result<Vec<u8>, EncodeError> {
+        self.try_encode(text)?.into_iter()
+            .map(|id| u8::try_from(id).map_err(|_| EncodeError::IdTooLarge { id, bits: u8::BITS }))
+            .collect()
+    }
+
+    /// Decodes ids produced by `encode_u8`.
+    pub fn decode_u8(&self, ids: &[u8]) -> Result<String, DecodeError> {
+        let ids: Vec<u32> = ids.iter().map(|&id| id as u32).collect();
+        self.decode_with(&ids, false)
+    }
+
+    /// Encodes using only the merges whose minted id is at most `max_rank`, which behaves like
+    /// the tokenizer would have with a vocab of `max_rank + 1` tokens, without retraining.
+    pub fn encode_with_rank_limit(&self, text: &str, max_rank: u32) -> Vec<u32> {
+        self.encode_up_to(text, max_rank).expect("Unknown char with UnknownPolicy::Error, use try_encode")
+    }
+
+    /// Encodes `text` into `path` as a flat array of little-endian ids of the given width,
+    /// the usual input format of training pipelines. Fails with `InvalidData` if an id does not fit the width,
+    /// or if the text cannot be encoded (see `try_encode`).
+    pub fn encode_to_file(&self, text: &str, path: &str, width: IdWidth) -> std::io::Result<()> {
+        let ids = self.try_encode(text).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if width == IdWidth::U16 && let Some(id) = ids.iter().find(|&&id| id > u16::MAX as u32) {
+            let error = EncodeError::IdTooLarge { id: *id, bits: u16::BITS };
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+        }
 
-        for (&idx, bytes) in self.vocab.iter() {
-            let content: std::borrow::Cow<str> = String::from_utf8_lossy(bytes);
-            writer.write_all(format!("[{}] -> ({})\n", idx, content).as_bytes())?;
+        let mut writer = BufWriter::new(std::fs::File::create(path)?);
+        for id in ids {
+            match width {
+                IdWidth::U16 => writer.write_all(&(id as u16).to_le_bytes())?,
+                IdWidth::U32 => writer.write_all(&id.to_le_bytes())?,
+            }
         }
-        writer.flush()?;
+        writer.flush()
+    }
+
+    /// Segmentation of `text` with the fewest possible tokens of the vocab, found by dynamic programming
+    /// over the token bytes instead of applying merges greedily by rank. It is slower than `encode` and
+    /// its ids may differ from it (and from what the model saw during training), so it is meant for research.
+    /// Besides the tokens of the vocab, each char can always be covered by the ids `encode` starts from, so an
+    /// untrained tokenizer gives raw bytes and unknown chars follow the `UnknownPolicy`.
+    pub fn encode_optimal(&self, text: &str) -> Vec<u32> {
+        let by_bytes: HashMap<&[u8], u32> = self.vocab.iter().map(|(&id, token)| (token.as_ref(), id)).collect();
+        let max_len = self.vocab.values().map(|token| token.len()).max().unwrap_or(1);
+        // Without byte fallback, a char-level model only has tokens made of whole chars
+        let whole_chars = self.alphabet == Alphabet::Chars && self.unknown != UnknownPolicy::ByteFallback;
+
+        let mut ids = Vec::with_capacity(text.len() + 2);
+        self.encode_segments(text, &mut ids, |piece, ids| {
+            let bytes = piece.as_bytes();
+            let mut base = self.initial_spans(piece, 0)?.into_iter().filter(|(_, range)| !range.is_empty()).peekable();
+            // best[i]: fewest tokens covering bytes[..i], with the id of the last one and where it starts
+            let mut best: Vec<Option<(usize, u32, usize)>> = vec![None; bytes.len() + 1];
+            best[0] = Some((0, 0, 0));
+            for end in 1..=bytes.len() {
+                let tokens = (end.saturating_sub(max_len)..end)
+                    .filter(|&start| !whole_chars || piece.is_char_boundary(start) && piece.is_char_boundary(end))
+                    .filter_map(|start| by_bytes.get(&bytes[start..end]).map(|&id| (start, id)));
+                let base_id = base.next_if(|(_, range)| range.end == end).map(|(id, range)| (range.start, id));
+                for (start, id) in tokens.chain(base_id) {
+                    let Some((count, _, _)) = best[start] else { continue };
+                    if best[end].is_none_or(|(best_count, _, _)| count + 1 < best_count) {
+                        best[end] = Some((count + 1, id, start));
+                    }
+                }
+            }
+
+            let mut piece_ids = Vec::new();
+            let mut end = bytes.len();
+            while end > 0 {
+                let (_, id, start) = best[end].expect("Base ids cover every char");
+                piece_ids.push(id);
+                end = start;
+            }
+            ids.extend(piece_ids.into_iter().rev());
+            Ok(())
+        }).expect("Unknown char with UnknownPolicy::Error, use try_encode");
+        ids
+    }
 
-        let file = std::fs::File::create(merges_path)?;
-        writer = BufWriter::new(file);
+    /// Same output as `encode`, but merges are looked up by binary search in a sorted array
+    /// instead of the `HashMap`, which tends to be faster thanks to memory locality.
+    pub fn encode_flat(&self, text: &str) -> Vec<u32> {
+        let rank = |pair: (u32, u32)| {
+            self.flat_merges.binary_search_by_key(&pair, |&(p, _)| p).ok().map(|i| self.flat_merges[i].1)
+        };
+        self.encode_with(text, rank).expect("Unknown char with UnknownPolicy::Error, use try_encode")
+    }
 
-        for (&(idx1, idx2), &idx_minted) in self.merges.iter() {
-            writer.write_all(format!("[{idx1}][{idx2}] -> [{idx_minted}]\n").as_bytes())?;
+    /// Same ids as `encode`, each with the byte range of `text` it comes from. BOS/EOS get empty ranges
+    /// at the start and the end. With a normalizer the ranges refer to the normalized text.
+    /// The ranges are tracked through the merges, so an unknown char replaced by `UnknownPolicy::Unk` covers
+    /// its whole char, and a word marker covers the space it stands for.
+    pub fn encode_with_offsets(&self, text: &str) -> Result<Vec<(u32, std::ops::Range<usize>)>, EncodeError> {
+        let text = self.normalize(text);
+        let mut tokens = Vec::with_capacity(text.len() + 2);
+        tokens.extend(self.bos.map(|id| (id, 0..0)));
+        for (segment, range) in self.segments(&text) {
+            match segment {
+                Segment::Special(id) => tokens.push((id, range)),
+                Segment::Piece(_) => {
+                    let initial = self.initial_spans(&text[range.clone()], range.start)?;
+                    tokens.extend(self.merge_spans(initial, |_, _| {}));
+                }
+            }
         }
-        writer.flush()?;
+        tokens.extend(self.eos.map(|id| (id, text.len()..text.len())));
+        Ok(tokens)
+    }
+
+    /// Index in the output of `encode` of the token each char of `text` belongs to, one entry per char.
+    /// A char split over several tokens, like an emoji left as raw bytes, belongs to the token holding its first byte.
+    /// With a normalizer the chars are those of the normalized text.
+    pub fn char_token_map(&self, text: &str) -> Vec<usize> {
+        let offsets = self.encode_with_offsets(text).expect("Unknown char with UnknownPolicy::Error, use try_encode");
+        let mut token = 0;
+        self.normalize(text).char_indices().map(|(at, _)| {
+            while offsets[token].1.is_empty() || offsets[token].1.end <= at { token += 1 }
+            token
+        }).collect()
+    }
+
+    /// Ids of the tokens lying entirely inside `range` of `full`, encoded with the merges that apply
+    /// in the full text rather than on the substring alone. BOS/EOS are left out.
+    pub fn encode_span(&self, full: &str, range: std::ops::Range<usize>) -> Vec<u32> {
+        self.encode_with_offsets(full).expect("Unknown char with UnknownPolicy::Error, use try_encode").into_iter()
+            .filter(|(_, offsets)| !offsets.is_empty() && range.start <= offsets.start && offsets.end <= range.end)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Same output as `encode`, along with how much merging it took.
+    pub fn encode_with_stats(&self, text: &str) -> (Vec<u32>, EncodeStats) {
+        let mut stats = EncodeStats::default();
+        let ids = self.encode_stepwise(text, |_, merged| {
+            stats.merges_applied += merged;
+            stats.iterations += 1;
+        });
+        (ids, stats)
+    }
+
+    /// Merges that fire at least once while encoding `corpus`.
+    pub fn used_merges(&self, corpus: &str) -> HashSet<(u32, u32)> {
+        let mut used = HashSet::new();
+        self.encode_stepwise(corpus, |pair, _| { used.insert(pair); });
+        used
+    }
+
+    /// Merges, by rank, that never fire on `corpus` even though their token occurs in it:
+    /// an earlier merge always consumes one of their halves first. The answer depends on the corpus,
+    /// so it should be representative before pruning the results.
+    pub fn shadowed_merges(&self, corpus: &str) -> Vec<(u32, u32)> {
+        let used = self.used_merges(corpus);
+        let corpus = corpus.as_bytes();
+        let mut shadowed: Vec<((u32, u32), u32)> = self.merges.iter()
+            .filter(|&(pair, _)| !used.contains(pair))
+            .filter(|&(_, idx)| {
+                let token = &self.vocab[idx];
+                corpus.windows(token.len()).any(|window| window == token.as_ref())
+            })
+            .map(|(&pair, &idx)| (pair, idx))
+            .collect();
+        shadowed.sort_unstable_by_key(|&(_, idx)| idx);
+        shadowed.into_iter().map(|(pair, _)| pair).collect()
+    }
+
+    // Same steps as `merge_ids`, each one merging every occurrence of the lowest ranked pair.
+    // `on_step` gets the pair and how many times it was merged
+    fn encode_stepwise(&self, text: &str, mut on_step: impl FnMut((u32, u32), usize)) -> Vec<u32> {
+        let mut ids = Vec::with_capacity(text.len() + 2);
+        self.encode_segments(text, &mut ids, |piece, ids| {
+            let mut chunk = self.initial_ids(piece)?;
+            while let Some((pair, idx)) = chunk.windows(2)
+                .filter_map(|w| self.merges.get(&(w[0], w[1])).map(|&idx| ((w[0], w[1]), idx)))
+                .min_by_key(|&(_, idx)| idx)
+            {
+                let len = chunk.len();
+                chunk = merge(&chunk, pair, idx);
+                on_step(pair, len - chunk.len());
+            }
+            ids.extend(chunk);
+            Ok(())
+        }).expect("Unknown char with UnknownPolicy::Error, use try_encode");
+        ids
+    }
+
+    /// Same output as `encode`, with the text cut in up to `parts` slices encoded on the rayon pool.
+    /// The whole text is normalized first, then cuts only land where no merge can cross, i.e. before a space
+    /// when a word marker is set. Without a marker the whole text is a single piece, so it is encoded serially.
+    pub fn encode_parallel(&self, text: &str, parts: usize) -> Vec<u32> {
+        let text = self.normalize(text);
+        let segments = self.segments(&text);
+        let starts: HashSet<usize> = segments.iter().map(|(_, range)| range.start).collect();
+        let points = split_points(&text, parts, |i| starts.contains(&i));
+        let encoded: Vec<Vec<u32>> = points.par_windows(2)
+            .map(|w| {
+                let first = segments.partition_point(|(_, range)| range.start < w[0]);
+                let last = segments.partition_point(|(_, range)| range.start < w[1]);
+                let mut ids = Vec::new();
+                for (segment, _) in &segments[first..last] {
+                    match segment {
+                        Segment::Special(id) => ids.push(*id),
+                        Segment::Piece(piece) => {
+                            let chunk = self.initial_ids(piece).expect("Unknown char with UnknownPolicy::Error, use try_encode");
+                            ids.extend(self.apply_merges(chunk, u32::MAX));
+                        }
+                    }
+                }
+                ids
+            })
+            .collect();
+        self.bos.into_iter().chain(encoded.into_iter().flatten()).chain(self.eos).collect()
+    }
 
+    fn encode_up_to(&self, text: &str, max_rank: u32) -> Result<Vec<u32>, EncodeError> {
+        self.encode_with(text, |pair| self.rank(pair, max_rank))
+    }
+
+    /// Same output as `encode`, written into a buffer taken from a thread-local pool and handed back to it
+    /// when the guard is dropped, so repeated encodes on a thread stop allocating their output.
+    /// Each thread has its own pool, so there is no locking; a guard dropped on another thread
+    /// just gives its buffer to that thread's pool.
+    pub fn encode_pooled(&self, text: &str) -> PooledIds {
+        let mut ids = ID_POOL.with_borrow_mut(|pool| pool.pop()).unwrap_or_default();
+        self.encode_into_with(text, |pair| self.rank(pair, u32::MAX), &mut ids)
+            .expect("Unknown char with UnknownPolicy::Error, use try_encode");
+        PooledIds { ids }
+    }
+
+    fn encode_with(&self, text: &str, rank: impl Fn((u32, u32)) -> Option<u32>) -> Result<Vec<u32>, EncodeError> {
+        let mut ids = Vec::new();
+        self.encode_into_with(text, rank, &mut ids)?;
+        Ok(ids)
+    }
+
+    // Replaces the content of `ids`, keeping its allocation
+    fn encode_into_with(&self, text: &str, rank: impl Fn((u32, u32)) -> Option<u32>, ids: &mut Vec<u32>) -> Result<(), EncodeError> {
+        ids.clear();
+        ids.reserve(text.len() + 2);
+        self.encode_segments(text, ids, |piece, ids| {
+            ids.extend(self.apply_merges_with(self.initial_ids(piece)?, &rank));
+            Ok(())
+        })
+    }
+
+    // The one pass every encode method goes through: normalizes the text, appends BOS, then the ids of each
+    // segment (special tokens as they are, pieces as given by `encode_piece`) and EOS to `ids`
+    fn encode_segments(&self, text: &str, ids: &mut Vec<u32>, mut encode_piece: impl FnMut(&str, &mut Vec<u32>) -> Result<(), EncodeError>) -> Result<(), EncodeError> {
+        let text = self.normalize(text);
+        ids.extend(self.bos);
+        for (segment, _) in self.segments(&text) {
+            match segment {
+                Segment::Special(id) => ids.push(id),
+                Segment::Piece(piece) => encode_piece(&piece, ids)?,
+            }
+        }
+        ids.extend(self.eos);
         Ok(())
     }
-}
 
+    // Normalized text cut in segments, each with its byte range: special tokens found in the text,
+    // and the pieces of the text between them
+    fn segments<'a>(&self, text: &'a str) -> Vec<(Segment<'a>, std::ops::Range<usize>)> {
+        let mut segments = Vec::new();
+        for (special, range) in self.split_specials(text) {
+            match special {
+                Some(id) => segments.push((Segment::Special(id), range)),
+                None => segments.extend(self.pieces(&text[range.clone()]).into_iter()
+                    .map(|(piece, at)| (Segment::Piece(piece), range.start + at.start..range.start + at.end))),
+            }
+        }
+        segments
+    }
 
-const GPT4_SPLIT_PATTERN: &str = concat!(
-    r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}+|\p{N}{1,3}|",
-    r" ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+(?!\S)|\s+"
-);
+    // `text` cut around the special tokens it contains: the id and range of each special, and the ranges
+    // (never empty) of the plain text between them, in order
+    fn split_specials(&self, text: &str) -> Vec<(Option<u32>, std::ops::Range<usize>)> {
+        let mut parts = Vec::new();
+        let mut at = 0;
+        while let Some((start, end, id)) = self.find_special(&text[at..]) {
+            if start > 0 {
+                parts.push((None, at..at + start));
+            }
+            parts.push((Some(id), at + start..at + end));
+            at += end;
+        }
+        if at < text.len() {
+            parts.push((None, at..text.len()));
+        }
+        parts
+    }
 
-struct RegexTokenizer {
-    inner: BasicTokenizer,
-    regex: Regex
-}
+    // Byte range and id of the first special token in `text`, according to the special matching
+    fn find_special(&self, text: &str) -> Option<(usize, usize, u32)> {
+        let matches = |candidate: &str, token: &str| match self.special_matching {
+            SpecialMatching::Off => false,
+            SpecialMatching::Exact => candidate == token,
+            SpecialMatching::IgnoreAsciiCase => candidate.eq_ignore_ascii_case(token),
+        };
+        if self.special_matching == SpecialMatching::Off || self.special_tokens.is_empty() {
+            return None
+        }
+        (0..text.len()).filter(|&i| text.is_char_boundary(i)).find_map(|i| {
+            self.special_tokens.iter()
+                .filter(|(token, _)| text.get(i..i + token.len()).is_some_and(|candidate| matches(candidate, token)))
+                .max_by_key(|&(token, &id)| (token.len(), std::cmp::Reverse(id)))
+                .map(|(token, &id)| (i, i + token.len(), id))
+        })
+    }
 
-impl RegexTokenizer {
-    fn new(pattern: &str) -> Result<Self, regex::Error> {
-        Ok(RegexTokenizer { inner: BasicTokenizer::new(), regex: Regex::new(pattern)? })
+    // Text split in the pieces merges are confined to: the whole text, or one piece per word with a word marker
+    fn pretokenize<'a>(&self, text: &'a str) -> Vec<Cow<'a, str>> {
+        let text = self.normalize(text);
+        if self.word_marker.is_none() { return vec![text] }
+        self.pieces(&text).into_iter().map(|(piece, _)| piece.into_owned().into()).collect()
     }
-}
 
-impl Tokenize for RegexTokenizer {
-    fn train(&mut self, text: &str, vocab_size: usize, verbose: bool) {
-        todo!()
+    // Pieces of already normalized text, each with the byte range of `text` it comes from. With a word marker
+    // a piece starts at every space (or marker already in the text) and spells the spaces with the marker
+    fn pieces<'a>(&self, text: &'a str) -> Vec<(Cow<'a, str>, std::ops::Range<usize>)> {
+        let Some(marker) = self.word_marker else {
+            return if text.is_empty() { Vec::new() } else { vec![(text.into(), 0..text.len())] }
+        };
+        let marked = |range: std::ops::Range<usize>| (text[range.clone()].replace(' ', marker.encode_utf8(&mut [0; 4])).into(), range);
+
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        for (i, c) in text.char_indices() {
+            if (c == ' ' || c == marker) && i > start {
+                pieces.push(marked(start..i));
+                start = i;
+            }
+        }
+        if start < text.len() {
+            pieces.push(marked(start..text.len()));
+        }
+        pieces
     }
 
-    fn encode(&self, text: &str) -> Vec<u32> {
-        todo!()
+    fn normalize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let text = match &self.normalizer {
+            Some(normalizer) => normalizer(text),
+            None => text.into()
+        };
+        if self.normalize_newlines && text.contains('\r') {
+            return text.replace("\r\n", "\n").replace('\r', "\n").into()
+        }
+        text
     }
 
-    fn decode(&self, ids: &[u32]) -> String {
-        todo!()
+    fn initial_chunks(&self, text: &str) -> Result<Vec<Vec<u32>>, EncodeError> {
+        self.pretokenize(text).iter().map(|piece| self.initial_ids(piece)).collect()
     }
 
-    fn save(&self, vocab_path: &str, merges_path: &str) -> std::io::Result<()> {
-        self.inner.save(vocab_path, merges_path)
+    // Ids the merges start from: plain bytes, or known chars in char-level mode
+    fn initial_ids(&self, text: &str) -> Result<Vec<u32>, EncodeError> {
+        if self.alphabet == Alphabet::Bytes {
+            return Ok(text.as_bytes().iter().map(|&b| b as u32).collect())
+        }
+
+        let mut ids = Vec::with_capacity(text.len());
+        for c in text.chars() {
+            self.push_char_ids(c, &mut ids)?;
+        }
+        Ok(ids)
     }
-}
 
+    // Ids of a single char in char-level mode
+    fn push_char_ids(&self, c: char, ids: &mut Vec<u32>) -> Result<(), EncodeError> {
+        if c.is_ascii() {
+            ids.push(c as u32);
+            return Ok(())
+        }
+        match (self.char_ids.get(&c), self.unknown) {
+            (Some(&id), _) => ids.push(id),
+            (None, UnknownPolicy::Error) => return Err(EncodeError::UnknownChar(c)),
+            (None, UnknownPolicy::Unk(id)) => ids.push(id),
+            (None, UnknownPolicy::ByteFallback) => {
+                ids.extend(c.encode_utf8(&mut [0; 4]).bytes().map(|b| b as u32));
+            }
+        }
+        Ok(())
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    // Initial ids of a piece given as it is in the text, before its spaces are replaced by the word marker,
+    // each with the byte range of the text it stands for. `at` is where the piece starts in the text
+    fn initial_spans(&self, piece: &str, at: usize) -> Result<Vec<(u32, std::ops::Range<usize>)>, EncodeError> {
+        let mut spans = Vec::with_capacity(piece.len());
+        let mut ids = Vec::new();
+        for (i, c) in piece.char_indices() {
+            let unit = match self.word_marker {
+                Some(marker) if c == ' ' => marker,
+                _ => c
+            };
+            ids.clear();
+            match self.alphabet {
+                Alphabet::Bytes => ids.extend(unit.encode_utf8(&mut [0; 4]).bytes().map(u32::from)),
+                Alphabet::Chars => self.push_char_ids(unit, &mut ids)?,
+            }
+            // The bytes of a char each cover their own byte. Otherwise (char token, unk, bytes of the marker)
+            // the first id covers the whole char and the others get an empty range after it
+            let (start, len) = (at + i, c.len_utf8());
+            for (k, &id) in ids.iter().enumerate() {
+                let range = match k {
+                    _ if unit == c && ids.len() == len => start + k..start + k + 1,
+                    0 => start..start + len,
+                    _ => start + len..start + len
+                };
+                spans.push((id, range));
+            }
+        }
+        Ok(spans)
+    }
 
-    #[test]
-    fn test_merge() {
-        let merged = merge(&[1, 2, 3, 4, 4, 4, 5, 6], (3, 4), 10);
-        assert_eq!(merged, [1, 2, 10, 4, 4, 5, 6]);
+    // Merges like `encode_stepwise`, the range of a merged token spans both halves
+    fn merge_spans(
+        &self,
+        mut tokens: Vec<(u32, std::ops::Range<usize>)>,
+        mut on_merge: impl FnMut(u32, &std::ops::Range<usize>)
+    ) -> Vec<(u32, std::ops::Range<usize>)> {
+        while let Some((pair, idx)) = tokens.windows(2)
+            .filter_map(|w| self.merges.get(&(w[0].0, w[1].0)).map(|&idx| ((w[0].0, w[1].0), idx)))
+            .min_by_key(|&(_, idx)| idx)
+        {
+            let mut merged = Vec::with_capacity(tokens.len());
+            let mut tokens_iter = tokens.into_iter().peekable();
+            while let Some((id, range)) = tokens_iter.next() {
+                match tokens_iter.next_if(|(next, _)| id == pair.0 && *next == pair.1) {
+                    Some((_, next)) => {
+                        let range = range.start..next.end;
+                        on_merge(idx, &range);
+                        merged.push((idx, range));
+                    }
+                    None => merged.push((id, range))
+                }
+            }
+            tokens = merged;
+        }
+        tokens
     }
 
-    #[test]
-    fn test_train() {
-        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
-        let mut tokenizer = BasicTokenizer::new();
-        tokenizer.train(&text, 500, false);
+    // Merges allowed below the rank limit
+    fn rank(&self, pair: (u32, u32), max_rank: u32) -> Option<u32> {
+        self.merges.get(&pair).copied().filter(|&idx| idx <= max_rank)
     }
 
-    #[test] 
-    fn test_encode() {
-        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
-        let mut tokenizer = BasicTokenizer::new();
-        tokenizer.train(&text, 500, false);
-        println!("{:?}", tokenizer.encode("Self driving is the future! 🙄"));
+    fn apply_merges(&self, ids: Vec<u32>, max_rank: u32) -> Vec<u32> {
+        self.apply_merges_with(ids, &|pair| self.rank(pair, max_rank))
     }
 
-    #[test] 
-    fn test_decode() {
-        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
-        let mut tokenizer = BasicTokenizer::new();
-        tokenizer.train(&text, 500, false);
-        let s = "Self driving is the future! 🙄";
+    // `rank` gives the minted id of a pair, or `None` when the pair must not be merged
+    fn apply_merges_with(&self, ids: Vec<u32>, rank: &impl Fn((u32, u32)) -> Option<u32>) -> Vec<u32> {
+        // Inputs dominated by runs of the same id (indentation, "aaaa...") are cheaper to merge as runs
+        let runs = run_lengths(&ids);
+        if runs.len() * 2 <= ids.len() {
+            return merge_runs(runs, rank)
+        }
+        merge_ids(ids, rank)
+    }
 
-        let encoded = tokenizer.encode(s);
-        assert_eq!(s, tokenizer.decode(&encoded));
+    /// BPE-dropout: every applicable merge is skipped with probability `dropout` at each step,
+    /// giving a different (still lossless) segmentation for each seed. The same seed always gives the same ids.
+    /// A `dropout` of 0 is the same as `encode`.
+    pub fn encode_with_dropout(&self, text: &str, dropout: f64, seed: u64) -> Vec<u32> {
+        let mut rng = SplitMix64::new(seed);
+        let mut ids = Vec::with_capacity(text.len() + 2);
+        self.encode_segments(text, &mut ids, |piece, ids| {
+            ids.extend(self.merge_with_dropout(self.initial_ids(piece)?, dropout, &mut rng));
+            Ok(())
+        }).expect("Unknown char with UnknownPolicy::Error");
+        ids
+    }
+
+    fn merge_with_dropout(&self, mut ids: Vec<u32>, dropout: f64, rng: &mut SplitMix64) -> Vec<u32> {
+        while ids.len() > 1 {
+            // Positions whose merge survived the dropout, along with the merge rank
+            let kept: Vec<(usize, u32)> = ids.windows(2).enumerate()
+                .filter_map(|(i, w)| self.merges.get(&(w[0], w[1])).map(|&idx| (i, idx)))
+                .filter(|_| rng.next_f64() >= dropout)
+                .collect();
+            let Some(&(_, best)) = kept.iter().min_by_key(|&&(_, idx)| idx) else { break };
+
+            let mut new_ids = Vec::with_capacity(ids.len());
+            let mut positions = kept.iter().filter(|&&(_, idx)| idx == best).map(|&(i, _)| i).peekable();
+            let mut i = 0;
+            while i < ids.len() {
+                while positions.peek().is_some_and(|&p| p < i) { positions.next(); }
+                if positions.peek() == Some(&i) {
+                    new_ids.push(best);
+                    i += 2;
+                }
+                else {
+                    new_ids.push(ids[i]);
+                    i += 1;
+                }
+            }
+            ids = new_ids;
+        }
+        ids
+    }
+
+    /// Same as `train`, but the full bigram counts (the initial one, plus the drift checks of debug builds) run on the given rayon pool.
+    /// Several trainings can share one bounded pool instead of each one spawning its own threads.
+    pub fn train_with_pool(&mut self, text: &str, vocab_size: usize, verbose: bool, pool: &rayon::ThreadPool) {
+        pool.install(|| self.train_loop(text, vocab_size, par_get_stats, &mut reporter(verbose, self.progress_format)));
+    }
+
+    /// Same as `train`, but `on_merge` is called after every minted token instead of printing.
+    pub fn train_with_progress(&mut self, text: &str, vocab_size: usize, mut on_merge: impl FnMut(&TrainProgress)) {
+        self.train_loop(text, vocab_size, seq_get_stats, &mut |progress| {
+            on_merge(progress);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Trains up to `max_vocab` tokens or until `time_budget` has elapsed, whichever comes first,
+    /// and returns the number of merges done. The clock is checked after each merge, so training
+    /// always stops between two merges and the model stays valid (with at least one merge when there is a pair).
+    pub fn train_for(&mut self, text: &str, max_vocab: usize, time_budget: std::time::Duration, verbose: bool) -> usize {
+        let start = std::time::Instant::now();
+        let mut report = reporter(verbose, self.progress_format);
+        let mut merges = 0;
+        self.train_loop(text, max_vocab, seq_get_stats, &mut |progress| {
+            merges = progress.step;
+            report(progress)?;
+            if start.elapsed() >= time_budget { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        merges
+    }
+
+    /// Same as `train`, but every merge is appended to `replay_path` (in the `merges.txt` format) and flushed
+    /// as soon as it is minted, so a crash only loses the merge in progress. `load` rebuilds the model from it.
+    pub fn train_with_replay(&mut self, text: &str, vocab_size: usize, verbose: bool, replay_path: &str) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(std::fs::File::create(replay_path)?);
+        let mut report = reporter(verbose, self.progress_format);
+        let mut result = Ok(());
+        self.train_loop(text, vocab_size, seq_get_stats, &mut |progress| {
+            let (idx1, idx2) = progress.pair;
+            let idx_minted = progress.minted_idx;
+            result = writer.write_all(format!("[{idx1}][{idx2}] -> [{idx_minted}]\n").as_bytes()).and_then(|_| writer.flush());
+            if result.is_err() { return ControlFlow::Break(()) }
+            report(progress)
+        });
+        result
+    }
+
+    /// Loads a byte-level model from a merges file written by `save` or `train_with_replay`.
+    /// The vocab is rebuilt by replaying the merges in rank order.
+    pub fn load(merges_path: &str) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        let content = std::fs::read_to_string(merges_path)?;
+
+        let mut ranked = Vec::new();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            ranked.push(parse_merge_line(line).ok_or_else(|| invalid(format!("malformed merge line {line:?}")))?);
+        }
+        Self::replay_merges(ranked).map_err(invalid)
+    }
+
+    /// Vocab id of a token given its bytes, special tokens aside.
+    /// The reverse map is built on the first call, unless it was loaded with `load_with_reverse_index`.
+    pub fn token_to_id(&self, bytes: &[u8]) -> Option<u32> {
+        self.reverse_vocab().get(bytes).copied()
+    }
+
+    /// Id of the single vocab token spelled `s`, to check that a word was merged. Unlike `encode`, which may
+    /// split the string, this gives `None` when no token has exactly these bytes. With a word marker the
+    /// marker has to be spelled out in `s` in place of the space.
+    pub fn id_of_token_str(&self, s: &str) -> Option<u32> {
+        self.token_to_id(s.as_bytes())
+    }
+
+    /// Writes the bytes to id index used by `token_to_id`, one `<hex bytes> <id>` line per token sorted by id,
+    /// so that `load_with_reverse_index` does not have to rebuild it. Worth it for huge vocabs only.
+    pub fn save_reverse_index(&self, path: &str) -> std::io::Result<()> {
+        let mut entries: Vec<(u32, &Bytes)> = self.reverse_vocab().iter().map(|(bytes, &id)| (id, bytes)).collect();
+        entries.sort_unstable();
+        let mut writer = BufWriter::new(std::fs::File::create(path)?);
+        for (id, bytes) in entries {
+            for byte in bytes.iter() {
+                write!(writer, "{byte:02x}")?;
+            }
+            writeln!(writer, " {id}")?;
+        }
+        writer.flush()
+    }
+
+    /// Same as `load`, with the reverse index of `save_reverse_index` read from `reverse_path` instead of
+    /// being rebuilt. The index must match the vocab rebuilt from the merges exactly.
+    pub fn load_with_reverse_index(merges_path: &str, reverse_path: &str) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        let tokenizer = Self::load(merges_path)?;
+        let content = std::fs::read_to_string(reverse_path)?;
+
+        let mut reverse = HashMap::with_capacity(tokenizer.vocab.len());
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let malformed = || invalid(format!("malformed reverse index line {line:?}"));
+            let (hex, id) = line.split_once(' ').ok_or_else(malformed)?;
+            let id: u32 = id.parse().map_err(|_| malformed())?;
+            let bytes = (0..hex.len()).step_by(2)
+                .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(malformed)?;
+            if tokenizer.vocab.get(&id).map(|token| token.as_ref()) != Some(bytes.as_slice()) {
+                return Err(invalid(format!("reverse index entry {line:?} does not match the vocab")))
+            }
+            reverse.insert(Bytes::from(bytes), id);
+        }
+        // Every entry matches the vocab, so the same count means every token is covered
+        if reverse.len() != tokenizer.vocab.len() {
+            return Err(invalid("reverse index does not cover the vocab".to_string()))
+        }
+        tokenizer.reverse_vocab.set(reverse).expect("Fresh model has no reverse index yet");
+        Ok(tokenizer)
+    }
+
+    /// Same output as `save`, written to any writers. Nothing is allocated per entry: ids are formatted
+    /// straight into the writer and token bytes are written as they are stored (only a token that is not
+    /// valid UTF-8 needs a lossy copy). Writers are not flushed.
+    /// Entries are sorted by id, special tokens after the vocab, so equal models always give identical files.
+    pub fn save_to(&self, vocab: &mut impl Write, merges: &mut impl Write) -> std::io::Result<()> {
+        for idx in self.contiguous_ids() {
+            write!(vocab, "[{idx}] -> (")?;
+            vocab.write_all(String::from_utf8_lossy(&self.vocab[&idx]).as_bytes())?;
+            vocab.write_all(b")\n")?;
+        }
+        let mut special: Vec<(u32, &str)> = self.inverse_special_tokens.iter().map(|(&idx, token)| (idx, token.as_str())).collect();
+        special.sort_unstable();
+        for (idx, token) in special {
+            writeln!(vocab, "[{idx}] -> ({token})")?;
+        }
+
+        let mut ranked: Vec<((u32, u32), u32)> = self.merges.iter().map(|(&pair, &idx)| (pair, idx)).collect();
+        ranked.sort_unstable_by_key(|&(_, idx)| idx);
+        for ((idx1, idx2), idx_minted) in ranked {
+            writeln!(merges, "[{idx1}][{idx2}] -> [{idx_minted}]")?;
+        }
+        Ok(())
+    }
+
+    /// Minimal SentencePiece `.model` (a serialized `ModelProto`) for tools built on SentencePiece, a BPE model
+    /// with byte fallback. Piece `i` stands for id `i` of this model, so ids can be exchanged as they are:
+    /// the 256 bytes are `<0x00>`..`<0xFF>`, the other tokens are spelled with spaces shown as `▁` and scored
+    /// `-id` (SentencePiece merges the highest score first, i.e. in rank order), special tokens are user defined.
+    /// Ids with no SentencePiece form (unused ids below the highest special, tokens that are not valid UTF-8
+    /// or spelled like an earlier piece) get an unused `<unusedN>` placeholder. The `<unk>` piece SentencePiece
+    /// requires comes last. `test_sentencepiece_loads`, ignored by default, loads the output with the Python package.
+    pub fn to_sentencepiece(&self) -> Vec<u8> {
+        // SentencePiece piece types
+        const NORMAL: i64 = 1;
+        const UNKNOWN: i64 = 2;
+        const USER_DEFINED: i64 = 4;
+        const UNUSED: i64 = 5;
+        const BYTE: i64 = 6;
+
+        let end = self.vocab.keys().chain(self.inverse_special_tokens.keys()).max().map_or(0, |&id| id + 1);
+        let mut pieces: Vec<(String, f32, i64)> = Vec::with_capacity(end as usize + 1);
+        let mut seen = HashSet::new();
+        for id in 0..end {
+            let piece = match self.inverse_special_tokens.get(&id) {
+                Some(special) => Some((special.clone(), 0.0, USER_DEFINED)),
+                // Only the byte tokens are a single byte, minted and char tokens are longer
+                None => self.vocab.get(&id).and_then(|token| match token.as_ref() {
+                    &[byte] => Some((format!("<0x{byte:02X}>"), 0.0, BYTE)),
+                    bytes => std::str::from_utf8(bytes).ok().map(|text| (text.replace(' ', "▁"), -(id as f32), NORMAL)),
+                })
+            };
+            let piece = piece.filter(|(piece, _, _)| seen.insert(piece.clone()));
+            pieces.push(piece.unwrap_or_else(|| (format!("<unused{id}>"), 0.0, UNUSED)));
+        }
+        let unk_id = pieces.len();
+        pieces.push(("<unk>".to_string(), 0.0, UNKNOWN));
+
+        let mut model = ProtoWriter(Vec::new());
+        for (piece, score, kind) in &pieces {
+            let mut message = ProtoWriter(Vec::new());
+            message.bytes_field(1, piece.as_bytes());
+            message.float_field(2, *score);
+            message.int_field(3, *kind);
+            model.bytes_field(1, &message.0);
+        }
+        // TrainerSpec: BPE, vocab size, byte fallback, unk id and no BOS/EOS
+        let mut trainer = ProtoWriter(Vec::new());
+        trainer.int_field(3, 2);
+        trainer.int_field(4, pieces.len() as i64);
+        trainer.int_field(35, 1);
+        trainer.int_field(40, unk_id as i64);
+        trainer.int_field(41, -1);
+        trainer.int_field(42, -1);
+        model.bytes_field(2, &trainer.0);
+        // NormalizerSpec: text is kept as is apart from the space escaping
+        let mut normalizer = ProtoWriter(Vec::new());
+        normalizer.bytes_field(1, b"identity");
+        normalizer.int_field(3, 0);
+        normalizer.int_field(4, 0);
+        normalizer.int_field(5, 1);
+        model.bytes_field(3, &normalizer.0);
+        model.0
+    }
+
+    /// Writes `to_sentencepiece` to `path`.
+    pub fn save_sentencepiece(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.to_sentencepiece())
+    }
+
+    /// Rebuilds a byte-level model from its merges alone: every token is the concatenation of its pair,
+    /// so replaying them in rank order gives back the whole vocab. Panics if a merge uses an id
+    /// that neither a base byte nor a lower ranked merge defines.
+    pub fn from_merges(merges: &[((u32, u32), u32)]) -> BasicTokenizer {
+        Self::replay_merges(merges.to_vec()).unwrap_or_else(|msg| panic!("Invalid merges: {msg}"))
+    }
+
+    // Rebuilds a byte-level model from its merges, replayed in rank order
+    fn replay_merges(mut ranked: Vec<((u32, u32), u32)>) -> Result<Self, String> {
+        ranked.sort_unstable_by_key(|&(_, idx)| idx);
+
+        let mut tokenizer = Self::new();
+        for i in tokenizer.base_byte_range() {
+            tokenizer.vocab.insert(i, Bytes::copy_from_slice(&[i as u8]));
+        }
+        for ((idx1, idx2), idx) in ranked {
+            let (Some(b1), Some(b2)) = (tokenizer.vocab.get(&idx1), tokenizer.vocab.get(&idx2)) else {
+                return Err(format!("merge [{idx1}][{idx2}] -> [{idx}] uses an unknown id"))
+            };
+            let token = concat_tokens(b1, b2);
+            tokenizer.vocab.insert(idx, token);
+            tokenizer.merges.insert((idx1, idx2), idx);
+        }
+        tokenizer.index_merges();
+        tokenizer.shrink_to_fit();
+        Ok(tokenizer)
+    }
+
+    /// Keeps merging until encoding `text` takes about `target_ratio` ids per byte of text
+    /// (e.g. 0.2 to compress to 20% of the byte count) and returns the ratio reached.
+    /// Training stops at the first merge that reaches the target, so the result is at or just below it,
+    /// unless no pair occurs more than once anymore: merging it would not pay for its vocab entry,
+    /// so training stops early above the target.
+    pub fn train_to_ratio(&mut self, text: &str, target_ratio: f64, verbose: bool) -> f64 {
+        let target_len = (target_ratio * text.len() as f64) as usize;
+        let mut len = text.len();
+        let pieces = self.pretokenize(text);
+        let pieces: Vec<&str> = pieces.iter().map(|piece| piece.as_ref()).collect();
+
+        let mut report = reporter(verbose, self.progress_format);
+        if len > target_len {
+            self.train_pieces(&pieces, None, 2, seq_get_stats, &mut |progress| {
+                len = progress.len;
+                report(progress)?;
+                if len <= target_len { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+            });
+        }
+        len as f64 / text.len().max(1) as f64
+    }
+
+    /// Smallest vocab size whose training on `text` encodes it in at most `ratio` ids per byte.
+    /// Training is greedy, so each model is a prefix of the bigger ones and a single training run
+    /// (up to the returned size) is enough, no search is needed. If the ratio cannot be reached,
+    /// gives the size at which training ran out of repeated pairs.
+    pub fn min_vocab_for_ratio(text: &str, ratio: f64) -> usize {
+        let mut tokenizer = Self::new();
+        tokenizer.train_to_ratio(text, ratio, false);
+        tokenizer.vocab.len().max(256)
+    }
+
+    /// Merges, in rank order, that `train` of a byte-level `BasicTokenizer::new()` would learn on `text`,
+    /// as `(pair, minted id)`. Only ids are tracked, no vocab bytes are built, so it is a cheap preview.
+    /// There are fewer than `vocab_size - 256` merges when training runs out of pairs first.
+    pub fn plan_merges(text: &str, vocab_size: usize) -> Vec<((u32, u32), u32)> {
+        let mut chunks = vec![text.bytes().map(u32::from).collect::<Vec<u32>>()];
+        let mut stats = seq_get_stats(&chunks);
+        let num_merges = vocab_size.saturating_sub(256).min(text.len());
+        let mut plan = Vec::with_capacity(num_merges);
+        for idx in (256..).take(num_merges) {
+            let Some(pair) = top_pair(&stats) else { break };
+            apply_delta(&mut stats, merge_chunks(&mut chunks, pair, idx));
+            plan.push((pair, idx));
+        }
+        plan
+    }
+
+    /// Ids of the base alphabet, the tokens merges start from: the 256 bytes, followed in char-level mode
+    /// by one token per char, which training always mints before any merge.
+    pub fn base_byte_range(&self) -> std::ops::Range<u32> {
+        0..256 + self.char_ids.len() as u32
+    }
+
+    /// Whether `id` is part of the base alphabet rather than minted by a merge (or a special token).
+    pub fn is_base_byte(&self, id: u32) -> bool {
+        self.base_byte_range().contains(&id)
+    }
+
+    /// Expands a token id down to its base bytes by walking the merges recursively.
+    /// Must always agree with the bytes stored in `vocab`, which makes it useful for validation.
+    pub fn base_bytes_of(&self, id: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.expand_into(id, &mut bytes);
+        bytes
+    }
+
+    /// Whether `s` encodes to exactly one token (BOS/EOS aside), e.g. to check that seeded or
+    /// prioritized strings made it into the vocab as a whole.
+    pub fn assert_single_token(&self, s: &str) -> bool {
+        let markers = usize::from(self.bos.is_some()) + usize::from(self.eos.is_some());
+        self.try_encode(s).is_ok_and(|ids| ids.len() == markers + 1)
+    }
+
+    /// Number of ids `encode` gives for `text`, BOS/EOS included.
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    /// Distribution of the token counts over a batch, e.g. to pick a sensible padding length.
+    /// An empty batch gives all zeros.
+    pub fn batch_length_stats(&self, texts: &[&str]) -> LengthStats {
+        let mut lengths: Vec<usize> = texts.iter().map(|text| self.count_tokens(text)).collect();
+        if lengths.is_empty() {
+            return LengthStats::default()
+        }
+        lengths.sort_unstable();
+        let n = lengths.len();
+        let median = if n % 2 == 1 { lengths[n / 2] as f64 } else { (lengths[n / 2 - 1] + lengths[n / 2]) as f64 / 2.0 };
+        LengthStats {
+            min: lengths[0],
+            max: lengths[n - 1],
+            mean: lengths.iter().sum::<usize>() as f64 / n as f64,
+            median,
+        }
+    }
+
+    /// Runs of the same id repeated at least twice in a row in the encoding of `text`, as `(id, length)`
+    /// in order of appearance. Long runs (e.g. indentation split in many identical tokens) point to merges the vocab lacks.
+    pub fn repeated_token_runs(&self, text: &str) -> Vec<(u32, usize)> {
+        run_lengths(&self.encode(text)).into_iter().filter(|&(_, n)| n >= 2).collect()
+    }
+
+    /// Token level LCS diff turning the encoding of `a` into the encoding of `b`,
+    /// handy to see how far a small edit of the text ripples through the tokens.
+    pub fn encoding_diff(&self, a: &str, b: &str) -> Vec<DiffOp> {
+        let (a, b) = (self.encode(a), self.encode(b));
+        // lcs[i][j] is the LCS length of a[i..] and b[j..]
+        let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+            }
+        }
+
+        let mut ops = Vec::with_capacity(a.len().max(b.len()));
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                ops.push(DiffOp::Equal(a[i]));
+                (i, j) = (i + 1, j + 1);
+            }
+            else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+                ops.push(DiffOp::Delete(a[i]));
+                i += 1;
+            }
+            else {
+                ops.push(DiffOp::Insert(b[j]));
+                j += 1;
+            }
+        }
+        ops
+    }
+
+    /// Fraction of the vocab (special tokens aside) that encoding `text` actually uses, from 0 to 1.
+    /// A low coverage on representative data suggests the vocab is larger than needed.
+    pub fn vocab_coverage(&self, text: &str) -> f64 {
+        let used: HashSet<u32> = self.encode(text).into_iter().filter(|id| self.vocab.contains_key(id)).collect();
+        used.len() as f64 / self.vocab.len().max(1) as f64
+    }
+
+    /// How many times each id shows up when encoding `corpus`.
+    pub fn token_frequencies(&self, corpus: &str) -> HashMap<u32, usize> {
+        let mut frequencies = HashMap::new();
+        for id in self.encode(corpus) {
+            *frequencies.entry(id).or_insert(0) += 1;
+        }
+        frequencies
+    }
+
+    /// Shannon entropy in bits of the id distribution of the encoded `corpus`.
+    /// Empty corpora have an entropy of 0.
+    pub fn token_entropy(&self, corpus: &str) -> f64 {
+        let frequencies = self.token_frequencies(corpus);
+        let total: usize = frequencies.values().sum();
+        frequencies.values()
+            .map(|&count| {
+                let p = count as f64 / total as f64;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// All vocab ids, ascending, whose bytes include `byte`. Handy to see which merges a byte takes part in.
+    pub fn tokens_containing(&self, byte: u8) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.vocab.iter()
+            .filter(|(_, token)| token.contains(&byte))
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Byte trie over the vocab tokens (special tokens excluded), for longest-prefix matching.
+    pub fn trie(&self) -> VocabTrie {
+        let mut trie = VocabTrie { nodes: vec![TrieNode::default()] };
+        for (&id, token) in &self.vocab {
+            let mut node = 0;
+            for &byte in token.iter() {
+                node = match trie.nodes[node].children.get(&byte) {
+                    Some(&child) => child,
+                    None => {
+                        trie.nodes.push(TrieNode::default());
+                        let child = trie.nodes.len() - 1;
+                        trie.nodes[node].children.insert(byte, child);
+                        child
+                    }
+                };
+            }
+            trie.nodes[node].id = Some(id);
+        }
+        trie
+    }
+
+    /// View of a single vocab entry or special token, `None` for unknown ids.
+    /// An id that is both is shown as the special token, like `decode` does.
+    pub fn token(&self, id: u32) -> Option<Token<'_>> {
+        self.token_bytes(&id).ok().map(|bytes| Token { id, bytes })
+    }
+
+    /// Stable hash of the model, the same across runs, platforms and Rust versions, to catch a mismatch
+    /// between the model used for training and for inference. It covers the merges in rank order and the
+    /// alphabet config (char tokens, word marker, newline normalization); special tokens and custom normalizers are left out.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        hasher.write(&[self.alphabet as u8, self.normalize_newlines as u8]);
+        hasher.write(&self.word_marker.map_or(u32::MAX, |marker| marker as u32).to_le_bytes());
+
+        let mut chars: Vec<(u32, char)> = self.char_ids.iter().map(|(&c, &id)| (id, c)).collect();
+        chars.sort_unstable();
+        for (id, c) in chars {
+            hasher.write(&id.to_le_bytes());
+            hasher.write(&(c as u32).to_le_bytes());
+        }
+        let mut ranked: Vec<((u32, u32), u32)> = self.merges.iter().map(|(&pair, &idx)| (pair, idx)).collect();
+        ranked.sort_unstable_by_key(|&(_, idx)| idx);
+        for ((a, b), idx) in ranked {
+            for id in [a, b, idx] {
+                hasher.write(&id.to_le_bytes());
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Checks the merges of a loaded or hand-edited model and returns every issue found, in rank order.
+    /// A model built by training always gives an empty list.
+    pub fn validate(&self) -> Vec<ModelIssue> {
+        let mut ranked: Vec<((u32, u32), u32)> = self.merges.iter().map(|(&pair, &idx)| (pair, idx)).collect();
+        ranked.sort_unstable_by_key(|&(_, idx)| idx);
+        ranked.into_iter()
+            .filter(|&((a, b), minted)| minted <= a || minted <= b)
+            .map(|(pair, minted)| ModelIssue::NonMonotonicMerge { pair, minted })
+            .collect()
+    }
+
+    /// All vocab ids in ascending order. After `prune` they are exactly `0..vocab_size`,
+    /// so they can be used directly as row indices of an embedding table.
+    pub fn contiguous_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.vocab.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Removes the given merges along with every merge built on top of them,
+    /// then renumbers the remaining tokens so that ids are contiguous again (ranks keep their order).
+    /// Ids used outside of the vocab (special tokens, BOS/EOS, the `UnknownPolicy::Unk` id) move right after it
+    /// in their order, and everything pointing at a token is renumbered with it. Priority pairs and grapheme
+    /// warnings keep only the entries whose ids still exist, and the pruned pairs are no longer prioritized.
+    pub fn prune(&mut self, pairs: &[(u32, u32)]) {
+        self.remove_merges(pairs, HashSet::new());
+    }
+
+    /// Copy of the model keeping only the merges that fire on `corpus`, renumbered like `prune`, to ship
+    /// a minimal model for a known domain. A merge only fires once its halves exist, so every kept merge
+    /// keeps its halves; the corpus encodes to the same tokens, with the new ids.
+    pub fn export_used_subset(&self, corpus: &str) -> BasicTokenizer {
+        let used = self.used_merges(corpus);
+        let unused: Vec<(u32, u32)> = self.merges.keys().copied().filter(|pair| !used.contains(pair)).collect();
+        let mut subset = self.clone();
+        subset.prune(&unused);
+        subset
+    }
+
+    /// Minted tokens, by id, that start or end inside a grapheme cluster when encoding `corpus`, e.g. a token
+    /// starting with the combining accent of the previous letter, or holding one half of a flag, a Hangul syllable
+    /// or an emoji sequence. Clusters approximate those of UAX #29, see `GRAPHEME_CLUSTER_PATTERN`.
+    /// Every merge applied along the way counts, not only the tokens left at the end.
+    /// Meant to be run on the training corpus once training is done; advisory only, the model is left as is.
+    pub fn grapheme_splitting_tokens(&self, corpus: &str) -> Result<Vec<u32>, EncodeError> {
+        let text = self.normalize(corpus);
+        let pieces: Vec<&str> = self.segments(&text).into_iter()
+            .filter_map(|(segment, range)| matches!(segment, Segment::Piece(_)).then(|| &text[range]))
+            .collect();
+        self.grapheme_cuts(&pieces)
+    }
+
+    // Minted tokens cutting a grapheme cluster when encoding `pieces`, clusters are those of the pieces laid end
+    // to end so that a cluster split by the pre-tokenization is caught too
+    fn grapheme_cuts(&self, pieces: &[&str]) -> Result<Vec<u32>, EncodeError> {
+        let text = pieces.concat();
+        let mut boundaries = HashSet::from([0]);
+        for cluster in GRAPHEME_CLUSTER.find_iter(&text) {
+            boundaries.insert(cluster.expect("Grapheme pattern never fails").end());
+        }
+        let mut ids = Vec::new();
+        let mut at = 0;
+        for piece in pieces {
+            let initial = self.initial_spans(piece, at)?;
+            self.merge_spans(initial, |idx, range| {
+                // Empty ranges only cover the extra ids of a char, they can't cut a cluster
+                let cuts = !boundaries.contains(&range.start) || !boundaries.contains(&range.end);
+                if !range.is_empty() && cuts {
+                    ids.push(idx);
+                }
+            });
+            at += piece.len();
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    /// Merges, in rank order, whose token is built on top of the token minted by `base`, directly or not.
+    /// `base` itself is not included, and an unknown pair gives nothing.
+    pub fn merges_from(&self, base: (u32, u32)) -> Vec<(u32, u32)> {
+        let Some(&minted) = self.merges.get(&base) else { return Vec::new() };
+        let mut ranked: Vec<((u32, u32), u32)> = self.merges.iter().map(|(&pair, &idx)| (pair, idx)).collect();
+        ranked.sort_unstable_by_key(|&(_, idx)| idx);
+
+        // Halves always have a lower rank than their merge, so one pass in rank order reaches everything
+        let mut reached = HashSet::from([minted]);
+        let mut merges = Vec::new();
+        for (pair, idx) in ranked {
+            if reached.contains(&pair.0) || reached.contains(&pair.1) {
+                reached.insert(idx);
+                merges.push(pair);
+            }
+        }
+        merges
+    }
+
+    /// Char-level mode only: removes the base tokens of chars that never occur in `corpus`,
+    /// along with every merge built on top of them, then renumbers like `prune`. Returns how many chars were removed.
+    /// The 256 byte tokens are never pruned, they are the fallback for every char and the whole alphabet
+    /// in byte-level mode, where this does nothing.
+    pub fn prune_unused_chars(&mut self, corpus: &str) -> usize {
+        if self.alphabet == Alphabet::Bytes {
+            return 0
+        }
+        let used: HashSet<char> = corpus.chars().collect();
+        let unused: Vec<char> = self.char_ids.keys().copied().filter(|c| !used.contains(c)).collect();
+        let mut removed = HashSet::new();
+        for c in &unused {
+            let id = self.char_ids.remove(c).expect("Char comes from the map");
+            self.vocab.remove(&id);
+            removed.insert(id);
+        }
+        self.remove_merges(&[], removed);
+        unused.len()
+    }
+
+    // Drops `pairs` and every merge depending on them or on an already `removed` id, then remaps
+    fn remove_merges(&mut self, pairs: &[(u32, u32)], mut removed: HashSet<u32>) {
+        let pairs: HashSet<(u32, u32)> = pairs.iter().copied().collect();
+        let mut ranked: Vec<((u32, u32), u32)> = self.merges.iter().map(|(&pair, &idx)| (pair, idx)).collect();
+        ranked.sort_unstable_by_key(|&(_, idx)| idx);
+
+        for (pair, idx) in ranked {
+            if pairs.contains(&pair) || removed.contains(&pair.0) || removed.contains(&pair.1) {
+                removed.insert(idx);
+                self.merges.remove(&pair);
+                self.vocab.remove(&idx);
+            }
+        }
+        self.priority_pairs.retain(|pair| !pairs.contains(pair));
+        self.remap_contiguous();
+    }
+
+    // Renumbers the tokens to 0.. following the current id order. The byte tokens are never removed,
+    // so they keep their ids and the remaining char tokens follow them as the rest of the base alphabet.
+    // Ids outside of the vocab come next, see `prune`
+    fn remap_contiguous(&mut self) {
+        let unk = match self.unknown {
+            UnknownPolicy::Unk(id) => Some(id),
+            _ => None
+        };
+        let mut outside: Vec<u32> = self.inverse_special_tokens.keys().copied()
+            .chain(self.bos)
+            .chain(self.eos)
+            .chain(unk)
+            .filter(|id| !self.vocab.contains_key(id))
+            .collect();
+        outside.sort_unstable();
+        outside.dedup();
+        let mapping: HashMap<u32, u32> = self.contiguous_ids().into_iter()
+            .chain(outside)
+            .enumerate()
+            .map(|(i, id)| (id, i as u32))
+            .collect();
+        let remap = |id: u32| *mapping.get(&id).unwrap_or(&id);
+
+        self.vocab = self.vocab.drain().map(|(id, bytes)| (remap(id), bytes)).collect();
+        self.merges = self.merges.drain().map(|((a, b), idx)| ((remap(a), remap(b)), remap(idx))).collect();
+        self.char_ids = self.char_ids.drain().map(|(c, id)| (c, remap(id))).collect();
+        self.special_tokens = self.special_tokens.drain().map(|(token, id)| (token, remap(id))).collect();
+        self.inverse_special_tokens = self.inverse_special_tokens.drain().map(|(id, token)| (remap(id), token)).collect();
+        self.bos = self.bos.map(remap);
+        self.eos = self.eos.map(remap);
+        if let Some(id) = unk {
+            self.unknown = UnknownPolicy::Unk(remap(id));
+        }
+        self.priority_pairs = self.priority_pairs.drain(..)
+            .filter_map(|(a, b)| Some((*mapping.get(&a)?, *mapping.get(&b)?)))
+            .collect();
+        if let Some(warnings) = &mut self.grapheme_warnings {
+            *warnings = warnings.iter().filter_map(|id| mapping.get(id).copied()).collect();
+        }
+        self.index_merges();
+    }
+
+    fn index_merges(&mut self) {
+        self.flat_merges = self.merges.iter().map(|(&pair, &idx)| (pair, idx)).collect();
+        self.flat_merges.sort_unstable();
+        self.reverse_vocab = std::sync::OnceLock::new();
+    }
+
+    fn reverse_vocab(&self) -> &HashMap<Bytes, u32> {
+        self.reverse_vocab.get_or_init(|| {
+            // Should two tokens share their bytes, the lowest id wins
+            let mut reverse = HashMap::with_capacity(self.vocab.len());
+            for id in self.contiguous_ids() {
+                reverse.entry(self.vocab[&id].clone()).or_insert(id);
+            }
+            reverse
+        })
+    }
+
+    fn expand_into(&self, id: u32, bytes: &mut Vec<u8>) {
+        // Base tokens are not made of merges, in char-level mode they hold a whole char
+        if self.is_base_byte(id) {
+            if let Some(token) = self.vocab.get(&id) {
+                bytes.extend_from_slice(token);
+            }
+            return
+        }
+        if let Some((&(left, right), _)) = self.merges.iter().find(|&(_, &minted)| minted == id) {
+            self.expand_into(left, bytes);
+            self.expand_into(right, bytes);
+        }
+    }
+
+    fn train_loop(&mut self, text: &str, vocab_size: usize, count: fn(&[Vec<u32>]) -> Stats, on_merge: &mut OnMerge) {
+        let pieces = self.pretokenize(text);
+        let pieces: Vec<&str> = pieces.iter().map(|piece| piece.as_ref()).collect();
+        self.train_pieces(&pieces, Some(vocab_size), 1, count, on_merge);
+    }
+
+    // Training over text that is already split in pieces, merges never cross two pieces.
+    // Without a vocab size, merging goes on until `on_merge` breaks or no pair is left
+    fn train_pieces(&mut self, pieces: &[&str], vocab_size: Option<usize>, min_frequency: u32, count: fn(&[Vec<u32>]) -> Stats, on_merge: &mut OnMerge) {
+        // Initialize vocab with single value bytes
+        for i in 0..256 {
+            self.vocab.insert(i, Bytes::copy_from_slice(&[i as u8]));
+        }
+
+        // In char-level mode every multi-byte char of the corpus gets its own base token
+        if self.alphabet == Alphabet::Chars {
+            let mut chars: Vec<char> = pieces.iter().flat_map(|piece| piece.chars()).filter(|c| !c.is_ascii()).collect();
+            chars.sort_unstable();
+            chars.dedup();
+            for c in chars {
+                let id = self.vocab.len() as u32;
+                self.vocab.insert(id, Bytes::copy_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()));
+                self.char_ids.insert(c, id);
+            }
+        }
+
+        // Every merge shortens the corpus by at least one id, so there can never be more merges than bytes
+        let corpus_len: usize = pieces.iter().map(|piece| piece.len()).sum();
+        let num_merges = vocab_size.map(|vocab_size| vocab_size.saturating_sub(self.vocab.len()).min(corpus_len));
+        let chunks = pieces.iter()
+            .map(|piece| self.initial_ids(piece))
+            .collect::<Result<_, _>>()
+            .expect("Training chars are always in the alphabet");
+        self.learn_merges(chunks, num_merges, min_frequency, count, on_merge);
+        self.index_merges();
+        self.shrink_to_fit();
+        if self.grapheme_warnings.is_some() {
+            self.grapheme_warnings = Some(self.grapheme_cuts(pieces).expect("Training chars are always in the alphabet"));
+        }
+    }
+
+    /// Learns `additional_merges` new merges on `text` on top of the existing ones.
+    /// The text is first encoded with the current merges so that existing ids stay untouched.
+    pub fn continue_training(&mut self, text: &str, additional_merges: usize, verbose: bool) {
+        if self.vocab.is_empty() {
+            for i in self.base_byte_range() {
+                self.vocab.insert(i, Bytes::copy_from_slice(&[i as u8]));
+            }
+        }
+        let chunks = self.initial_chunks(text).expect("Unknown char with UnknownPolicy::Error");
+        let chunks = chunks.into_iter().map(|chunk| self.apply_merges(chunk, u32::MAX)).collect();
+        self.learn_merges(chunks, Some(additional_merges), 1, seq_get_stats, &mut reporter(verbose, self.progress_format));
+        self.index_merges();
+        self.shrink_to_fit();
+        if self.grapheme_warnings.is_some() {
+            let pieces = self.pretokenize(text);
+            let pieces: Vec<&str> = pieces.iter().map(|piece| piece.as_ref()).collect();
+            self.grapheme_warnings = Some(self.grapheme_cuts(&pieces).expect("Unknown char with UnknownPolicy::Error"));
+        }
+    }
+
+    /// Like `continue_training`, but leaves `base` untouched and returns the extended copy.
+    /// The merges of `base` keep their ranks, the new ones come after them.
+    pub fn train_from_base(base: &BasicTokenizer, text: &str, additional: usize, verbose: bool) -> BasicTokenizer {
+        let mut tokenizer = base.clone();
+        tokenizer.continue_training(text, additional, verbose);
+        tokenizer
+    }
+
+    // Mints up to `num_merges` tokens (without limit for `None`), stopping early once the top pair occurs
+    // less than `min_frequency` times or when `on_merge` breaks
+    fn learn_merges(&mut self, mut chunks: Vec<Vec<u32>>, num_merges: Option<usize>, min_frequency: u32, count: fn(&[Vec<u32>]) -> Stats, on_merge: &mut OnMerge) {
+        let mut priority = self.priority_pairs.clone().into_iter();
+        let start = self.timed_progress.then(std::time::Instant::now);
+        // Counted once, then kept up to date with the changes of each merge
+        let mut stats = count(&chunks);
+        let mut i = 0;
+        while num_merges.is_none_or(|num_merges| i < num_merges) {
+
+            // Prioritized pairs go first, whatever their frequency
+            let forced = priority.by_ref()
+                .find(|pair| self.vocab.contains_key(&pair.0) && self.vocab.contains_key(&pair.1));
+            
+            // Take the bigram that occuress more oftent
+            let pair = match forced {
+                Some(pair) => pair,
+                None => {
+                    let Some(pair) = top_pair(&stats) else { break };
+                    if stats[&pair] < min_frequency { break }
+                    pair
+                }
+            };
+
+            // An inconsistent model may already know this pair, apply it with its id instead of minting a duplicate
+            if let Some(&existing_idx) = self.merges.get(&pair) {
+                apply_delta(&mut stats, merge_chunks(&mut chunks, pair, existing_idx));
+                continue
+            }
+            
+            let minted_idx = self.vocab.len() as u32;
+            let delta = merge_chunks(&mut chunks, pair, minted_idx);
+
+            // Concatenate bytes pair
+            let concat_bytes = concat_tokens(&self.vocab[&pair.0], &self.vocab[&pair.1]);
+
+            self.vocab.insert(minted_idx, concat_bytes);
+            self.merges.insert(pair, minted_idx);
+
+            i += 1;
+            let flow = on_merge(&TrainProgress {
+                step: i,
+                total: num_merges,
+                pair,
+                minted_idx,
+                frequency: stats.get(&pair).copied().unwrap_or(0),
+                token: &self.vocab[&minted_idx],
+                stats: &stats,
+                len: chunks.iter().map(|chunk| chunk.len()).sum(),
+                // Remaining merges at the average pace so far
+                eta: start.zip(num_merges).map(|(start, num_merges)| start.elapsed() / i as u32 * (num_merges - i) as u32)
+            });
+            if flow.is_break() { break }
+
+            // The callback saw the counts the pair was picked from, they can be updated now
+            apply_delta(&mut stats, delta);
+            if cfg!(debug_assertions) && i % STATS_CHECK_INTERVAL == 0 {
+                debug_assert_eq!(stats, count(&chunks), "incremental bigram counts drifted after {i} merges");
+            }
+        }
+    }
+}
+
+/// A token as stored in the vocab, displayed as `id=300 bytes=[65,66] text="AB"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub id: u32,
+    pub bytes: &'a [u8],
+}
+
+impl std::fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| b.to_string()).collect();
+        write!(f, "id={} bytes=[{}] text={:?}", self.id, bytes.join(","), String::from_utf8_lossy(self.bytes))
+    }
+}
+
+/// Byte trie of the vocab built by `BasicTokenizer::trie`, nodes are stored in one flat vector.
+#[derive(Debug, Clone)]
+pub struct VocabTrie {
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    children: HashMap<u8, usize>,
+    id: Option<u32>,
+}
+
+impl VocabTrie {
+    /// Longest vocab token that is a prefix of `bytes`, with its length in bytes.
+    /// This is greedy matching, not BPE: the result does not have to agree with `encode`.
+    pub fn longest_token_prefix(&self, bytes: &[u8]) -> Option<(u32, usize)> {
+        let mut node = 0;
+        let mut longest = None;
+        for (i, byte) in bytes.iter().enumerate() {
+            let Some(&child) = self.nodes[node].children.get(byte) else { break };
+            node = child;
+            if let Some(id) = self.nodes[node].id {
+                longest = Some((id, i + 1));
+            }
+        }
+        longest
+    }
+}
+
+// Buffers each thread keeps around for `encode_pooled`
+const ID_POOL_SIZE: usize = 8;
+
+thread_local! {
+    static ID_POOL: std::cell::RefCell<Vec<Vec<u32>>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Ids returned by `encode_pooled`, the buffer goes back to the pool on drop.
+pub struct PooledIds {
+    ids: Vec<u32>,
+}
+
+impl std::ops::Deref for PooledIds {
+    type Target = [u32];
+
+    fn deref(&self) -> &[u32] {
+        &self.ids
+    }
+}
+
+impl Drop for PooledIds {
+    fn drop(&mut self) {
+        let ids = std::mem::take(&mut self.ids);
+        ID_POOL.with_borrow_mut(|pool| {
+            if pool.len() < ID_POOL_SIZE { pool.push(ids) }
+        });
+    }
+}
+
+/// Incremental decoding built by `BasicTokenizer::stream_decoder`. Bytes that do not form a complete
+/// char yet are kept until the next ids complete them.
+pub struct StreamDecoder<'a> {
+    tokenizer: &'a BasicTokenizer,
+    pending: Vec<u8>,
+    trailing: TrailingBytes,
+}
+
+impl StreamDecoder<'_> {
+    /// Text completed by `id`, possibly empty.
+    pub fn push(&mut self, id: u32) -> Result<String, DecodeError> {
+        self.pending.extend_from_slice(self.tokenizer.token_bytes(&id)?);
+        let complete = match std::str::from_utf8(&self.pending) {
+            Ok(_) => self.pending.len(),
+            // An incomplete char can only be at the end, anything else is invalid for good
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err(DecodeError::InvalidUtf8(String::from_utf8(self.pending.clone()).unwrap_err()))
+        };
+        let rest = self.pending.split_off(complete);
+        let text = String::from_utf8(std::mem::replace(&mut self.pending, rest)).expect("Checked above");
+        Ok(self.tokenizer.unmark(text))
+    }
+
+    /// Ends the stream. The text is empty or a replacement char, and the bytes are only non-empty
+    /// with `TrailingBytes::Return`; with `TrailingBytes::Error` leftover bytes are an error.
+    pub fn finish(self) -> Result<(String, Vec<u8>), DecodeError> {
+        if self.pending.is_empty() {
+            return Ok((String::new(), Vec::new()))
+        }
+        match self.trailing {
+            TrailingBytes::Error => Err(DecodeError::InvalidUtf8(String::from_utf8(self.pending).unwrap_err())),
+            TrailingBytes::Replace => Ok((char::REPLACEMENT_CHARACTER.to_string(), Vec::new())),
+            TrailingBytes::Return => Ok((String::new(), self.pending)),
+        }
+    }
+}
+
+/// One step of `encoding_diff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffOp {
+    /// Id present in both encodings.
+    Equal(u32),
+    /// Id only in the first encoding.
+    Delete(u32),
+    /// Id only in the second encoding.
+    Insert(u32),
+}
+
+/// Token counts of a batch, see `batch_length_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LengthStats {
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub median: f64,
+}
+
+/// Work done by `encode_with_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncodeStats {
+    /// Pairs replaced by their merged id, each one shortens the output by one id.
+    pub merges_applied: usize,
+    /// Passes over the ids, one per distinct merge applied.
+    pub iterations: usize,
+}
+
+/// Snapshot handed to the training callback after each merge.
+pub struct TrainProgress<'a> {
+    /// Number of merges done so far, starting at 1.
+    pub step: usize,
+    /// Number of merges training aims for, `None` when it stops on another target, like `train_to_ratio`.
+    pub total: Option<usize>,
+    pub pair: (u32, u32),
+    pub minted_idx: u32,
+    /// How many times `pair` occurred when it was picked.
+    pub frequency: u32,
+    pub token: &'a [u8],
+    /// Bigram counts the pair was picked from. Only borrowed, so it costs nothing unless the callback copies it.
+    pub stats: &'a HashMap<(u32, u32), u32>,
+    /// Length of the training corpus in ids after this merge.
+    pub len: usize,
+    /// Estimated time left, only with `with_timed_progress` and a known `total`.
+    pub eta: Option<std::time::Duration>,
+}
+
+// Internal training callback, breaking stops the training after the current merge
+type OnMerge<'a> = dyn FnMut(&TrainProgress) -> ControlFlow<()> + 'a;
+
+/// Display options of the lines printed by verbose training. Only the output changes, never the training.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProgressFormat {
+    /// Shows tokens lowercased, easier to scan in long logs while training stays case-sensitive.
+    pub lowercase: bool,
+    /// Only `step/total`, without the percentage.
+    pub raw_counts: bool,
+    /// Adds how many times the merged pair occurred.
+    pub show_frequency: bool,
+    /// Leaves the token text out, which may be a lossy rendering of bytes that are not UTF-8.
+    pub hide_text: bool,
+}
+
+impl ProgressFormat {
+    /// Percentage and token text, the format of `Default`.
+    pub const STANDARD: ProgressFormat = ProgressFormat { lowercase: false, raw_counts: false, show_frequency: false, hide_text: false };
+    /// Everything, including the frequency of each merged pair.
+    pub const DETAILED: ProgressFormat = ProgressFormat { show_frequency: true, ..Self::STANDARD };
+    /// Counts and ids only, safe for logs that must stay plain ASCII.
+    pub const IDS_ONLY: ProgressFormat = ProgressFormat { raw_counts: true, hide_text: true, ..Self::STANDARD };
+
+    pub fn format(&self, progress: &TrainProgress) -> String {
+        let mut line = progress.step.to_string();
+        if let Some(total) = progress.total {
+            line.push_str(&format!("/{total}"));
+            if !self.raw_counts {
+                line.push_str(&format!(" - {:.2}%", progress.step as f64 / total as f64 * 100.0));
+            }
+        }
+        if let Some(eta) = progress.eta {
+            line.push_str(&format!(" - ETA {:.1}s", eta.as_secs_f64()));
+        }
+        line.push_str(&format!("\nMerged [{}] [{}] -> [{}]", progress.pair.0, progress.pair.1, progress.minted_idx));
+        if !self.hide_text {
+            let token = String::from_utf8_lossy(progress.token);
+            let token = if self.lowercase { token.to_lowercase() } else { token.into_owned() };
+            line.push_str(&format!(" ({token})"));
+        }
+        if self.show_frequency {
+            line.push_str(&format!(" x{}", progress.frequency));
+        }
+        line
+    }
+}
+
+// One extended grapheme cluster, an approximation of UAX #29 (the regex of table 1b plus rule GB9c) written with
+// the Grapheme_Cluster_Break properties of the regex engine's Unicode tables, as unicode-segmentation is not a
+// dependency. The engine has no Indic_Conjunct_Break property, so for GB9c the consonants and linkers are listed
+// from Unicode 16.0 and any Extend or ZWJ char stands in for InCB=Extend. Anything else is a cluster of one char
+// InCB=Consonant: Devanagari, Bengali, Gujarati, Oriya, Telugu and Malayalam consonants
+macro_rules! incb_consonant {
+    () => { concat!(
+        r"\u{915}-\u{939}\u{958}-\u{95F}\u{978}-\u{97F}",
+        r"\u{995}-\u{9A8}\u{9AA}-\u{9B0}\u{9B2}\u{9B6}-\u{9B9}\u{9DC}\u{9DD}\u{9DF}\u{9F0}\u{9F1}",
+        r"\u{A95}-\u{AA8}\u{AAA}-\u{AB0}\u{AB2}\u{AB3}\u{AB5}-\u{AB9}\u{AF9}",
+        r"\u{B15}-\u{B28}\u{B2A}-\u{B30}\u{B32}\u{B33}\u{B35}-\u{B39}\u{B5C}\u{B5D}\u{B5F}\u{B71}",
+        r"\u{C15}-\u{C28}\u{C2A}-\u{C39}\u{C58}-\u{C5A}\u{D15}-\u{D3A}"
+    ) };
+}
+
+// InCB=Linker: the viramas of the same scripts
+macro_rules! incb_linker {
+    () => { r"\u{94D}\u{9CD}\u{ACD}\u{B4D}\u{C4D}\u{D4D}" };
+}
+
+const GRAPHEME_CLUSTER_PATTERN: &str = concat!(
+    r"\r\n|\p{gcb=Control}|\p{gcb=Prepend}*(?:",
+    r"\p{gcb=L}*(?:\p{gcb=V}+|\p{gcb=LV}\p{gcb=V}*|\p{gcb=LVT})\p{gcb=T}*|\p{gcb=L}+|\p{gcb=T}+",
+    r"|\p{gcb=RI}\p{gcb=RI}",
+    r"|\p{Extended_Pictographic}(?:\p{gcb=Extend}*\p{gcb=ZWJ}\p{Extended_Pictographic})*",
+    // GB9c: consonants joined by a virama, e.g. क्ष
+    r"|[", incb_consonant!(), r"](?:[\p{gcb=Extend}\p{gcb=ZWJ}]*[", incb_linker!(), r"][\p{gcb=Extend}\p{gcb=ZWJ}]*",
+    r"[", incb_consonant!(), r"])*",
+    r"|[^\p{gcb=Control}\r\n])[\p{gcb=Extend}\p{gcb=ZWJ}\p{gcb=SpacingMark}]*",
+    r"|(?s:.)"
+);
+
+static GRAPHEME_CLUSTER: std::sync::LazyLock<Regex> =
+    std::sync::LazyLock::new(|| Regex::new(GRAPHEME_CLUSTER_PATTERN).expect("Grapheme pattern is valid"));
+
+// Callback used by the `verbose` flag of the training methods
+fn reporter(verbose: bool, format: ProgressFormat) -> impl FnMut(&TrainProgress) -> ControlFlow<()> {
+    move |progress| {
+        if verbose { println!("{}", format.format(progress)) }
+        ControlFlow::Continue(())
+    }
+}
+
+// Parses a `[idx1][idx2] -> [idx_minted]` line of the merges file
+fn parse_merge_line(line: &str) -> Option<((u32, u32), u32)> {
+    let (pair, minted) = line.trim().split_once(" -> ")?;
+    let (idx1, idx2) = pair.strip_prefix('[')?.strip_suffix(']')?.split_once("][")?;
+    let minted = minted.strip_prefix('[')?.strip_suffix(']')?;
+    Some(((idx1.parse().ok()?, idx2.parse().ok()?), minted.parse().ok()?))
+}
+
+/// Reads back the ids written by `BasicTokenizer::encode_to_file`.
+pub fn read_ids(path: &str, width: IdWidth) -> std::io::Result<Vec<u32>> {
+    let bytes = std::fs::read(path)?;
+    let size = match width {
+        IdWidth::U16 => 2,
+        IdWidth::U32 => 4,
+    };
+    if bytes.len() % size != 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "file size is not a multiple of the id width"))
+    }
+    Ok(bytes.chunks_exact(size).map(|chunk| match width {
+        IdWidth::U16 => u16::from_le_bytes([chunk[0], chunk[1]]) as u32,
+        IdWidth::U32 => u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+    }).collect())
+}
+
+// 64-bit FNV-1a over explicit little-endian bytes, unlike `std::hash` whose output may change between releases
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Small seeded generator so randomized features are reproducible across runs and platforms
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Protobuf wire format writer, just the field types the SentencePiece export needs
+struct ProtoWriter(Vec<u8>);
+
+impl ProtoWriter {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn int_field(&mut self, field: u32, value: i64) {
+        self.varint((field as u64) << 3);
+        self.varint(value as u64);
+    }
+
+    fn float_field(&mut self, field: u32, value: f32) {
+        self.varint((field as u64) << 3 | 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes_field(&mut self, field: u32, bytes: &[u8]) {
+        self.varint((field as u64) << 3 | 2);
+        self.varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+}
+
+impl Default for BasicTokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Concatenates the bytes of two tokens into a new token, like training does when minting a merge.
+///
+/// ```
+/// use bytes::Bytes;
+/// use toktokenizer::tokenizer::concat_tokens;
+///
+/// let token = concat_tokens(&Bytes::from_static(b"th"), &Bytes::from_static(b"e "));
+/// assert_eq!(token, Bytes::from_static(b"the "));
+/// ```
+pub fn concat_tokens(a: &Bytes, b: &Bytes) -> Bytes {
+    let mut buf: BytesMut = BytesMut::with_capacity(a.len() + b.len());
+    buf.extend_from_slice(a.as_ref());
+    buf.extend_from_slice(b.as_ref());
+    buf.freeze()
+}
+
+fn merge(ids: &[u32], pair: (u32, u32), idx: u32) -> Vec<u32> {
+    let mut new_ids: Vec<u32> = Vec::with_capacity(ids.len());
+    
+    // Sliding window of size 2 to get all bigrams, a merged pair consumes both of its ids
+    let mut i = 0;
+    while i < ids.len() {
+        if i + 1 < ids.len() && ids[i] == pair.0 && ids[i + 1] == pair.1 {
+            new_ids.push(idx);
+            i += 2;
+        }
+        else {
+            new_ids.push(ids[i]);
+            i += 1;
+        }
+    }
+
+    new_ids
+}
+
+fn merge_ids(mut ids: Vec<u32>, rank: &impl Fn((u32, u32)) -> Option<u32>) -> Vec<u32> {
+    while ids.len() > 1 {
+        let bigrams: Vec<&[u32]> = ids.windows(2).collect();
+        
+        // Get the merged pair with the lowest idx (which is a reference to a reference to a slice)
+        let pair = bigrams.iter().min_by_key(
+            |&&bigram| rank((bigram[0], bigram[1])).unwrap_or(u32::MAX)
+        );
+
+        match pair {
+            Some(&pair) => {
+                let (idx1, idx2) = (pair[0], pair[1]);
+                let Some(idx) = rank((idx1, idx2)) else { break };
+                ids = merge(&ids, (idx1, idx2), idx);
+            }
+            None => { break }
+        }
+    }
+    ids
+}
+
+// Same merges as `merge_ids`, applied to a run-length encoded sequence: a run of `n` ids `x`
+// becomes `n / 2` merged ids (plus a trailing `x` if `n` is odd) in one step
+fn merge_runs(mut runs: Vec<(u32, usize)>, rank: &impl Fn((u32, u32)) -> Option<u32>) -> Vec<u32> {
+    loop {
+        let mut best: Option<((u32, u32), u32)> = None;
+        let mut consider = |pair: (u32, u32)| {
+            if let Some(idx) = rank(pair) && best.is_none_or(|(_, best_idx)| idx < best_idx) {
+                best = Some((pair, idx));
+            }
+        };
+        for (i, &(id, n)) in runs.iter().enumerate() {
+            if n >= 2 { consider((id, id)) }
+            if let Some(&(next, _)) = runs.get(i + 1) { consider((id, next)) }
+        }
+        let Some((pair, idx)) = best else { break };
+
+        let mut new_runs = Vec::with_capacity(runs.len());
+        if pair.0 == pair.1 {
+            for &(id, n) in &runs {
+                if id == pair.0 {
+                    push_run(&mut new_runs, idx, n / 2);
+                    push_run(&mut new_runs, id, n % 2);
+                }
+                else {
+                    push_run(&mut new_runs, id, n);
+                }
+            }
+        }
+        else {
+            // The last id of a `pair.0` run merges with the first id of the following `pair.1` run
+            let mut first_consumed = false;
+            for (i, &(id, mut n)) in runs.iter().enumerate() {
+                if first_consumed {
+                    n -= 1;
+                    first_consumed = false;
+                }
+                if id == pair.0 && runs.get(i + 1).is_some_and(|&(next, _)| next == pair.1) {
+                    push_run(&mut new_runs, id, n - 1);
+                    push_run(&mut new_runs, idx, 1);
+                    first_consumed = true;
+                }
+                else {
+                    push_run(&mut new_runs, id, n);
+                }
+            }
+        }
+        runs = new_runs;
+    }
+    runs.into_iter().flat_map(|(id, n)| std::iter::repeat_n(id, n)).collect()
+}
+
+fn run_lengths(ids: &[u32]) -> Vec<(u32, usize)> {
+    let mut runs = Vec::new();
+    for &id in ids {
+        push_run(&mut runs, id, 1);
+    }
+    runs
+}
+
+// Appends `n` copies of `id`, extending the last run when it holds the same id
+fn push_run(runs: &mut Vec<(u32, usize)>, id: u32, n: usize) {
+    if n == 0 { return }
+    match runs.last_mut() {
+        Some((last, count)) if *last == id => *count += n,
+        _ => runs.push((id, n)),
+    }
+}
+
+fn get_stats(ids: &[u32], stats: &mut HashMap<(u32, u32), u32>) {
+    for window in ids.windows(2) {
+        *stats.entry((window[0], window[1])).or_insert(0) += 1;
+    }
+}
+
+// Merges `pair` in every chunk and returns how the bigram counts change, so that training can update
+// its stats instead of recounting everything: only the bigrams touching a merged position change.
+// Chunks without the pair are left untouched.
+// A new chunk is sized after the old one, so a chunk that shrinks a lot (e.g. a run halved by
+// a single merge) gets compacted, keeping the peak memory of long trainings close to the actual ids
+fn merge_chunks(chunks: &mut [Vec<u32>], pair: (u32, u32), idx: u32) -> HashMap<(u32, u32), i64> {
+    let mut delta = HashMap::new();
+    // Counts each bigram starting at one of `starts` (ascending) once, even when two merges share it
+    let mut count = |ids: &[u32], starts: &mut dyn Iterator<Item = usize>, change: i64| {
+        let mut last = None;
+        for w in starts {
+            if w + 1 < ids.len() && last.is_none_or(|last| w > last) {
+                *delta.entry((ids[w], ids[w + 1])).or_insert(0) += change;
+                last = Some(w);
+            }
+        }
+    };
+
+    for chunk in chunks.iter_mut() {
+        let Some(first) = chunk.windows(2).position(|window| (window[0], window[1]) == pair) else { continue };
+        let mut new_ids = Vec::with_capacity(chunk.len());
+        new_ids.extend_from_slice(&chunk[..first]);
+        let (mut merged_at, mut minted_at) = (Vec::new(), Vec::new());
+        let mut i = first;
+        while i < chunk.len() {
+            if i + 1 < chunk.len() && (chunk[i], chunk[i + 1]) == pair {
+                merged_at.push(i);
+                minted_at.push(new_ids.len());
+                new_ids.push(idx);
+                i += 2;
+            }
+            else {
+                new_ids.push(chunk[i]);
+                i += 1;
+            }
+        }
+        // Only the bigrams touching a merged position change: out with the old ones, in with the new ones
+        count(chunk, &mut merged_at.iter().flat_map(|&i| i.saturating_sub(1)..i + 2), -1);
+        count(&new_ids, &mut minted_at.iter().flat_map(|&j| j.saturating_sub(1)..j + 1), 1);
+
+        if new_ids.len() * 2 <= new_ids.capacity() {
+            new_ids.shrink_to_fit();
+        }
+        *chunk = new_ids;
+    }
+    delta
+}
+
+fn apply_delta(stats: &mut Stats, delta: HashMap<(u32, u32), i64>) {
+    for (pair, change) in delta {
+        let count = stats.get(&pair).map_or(0, |&count| count as i64) + change;
+        debug_assert!(count >= 0, "bigram {pair:?} counted below zero");
+        if count > 0 { stats.insert(pair, count as u32); } else { stats.remove(&pair); }
+    }
+}
+
+// Byte offsets from 0 to `text.len()` cutting it in up to `parts` slices of about the same size.
+// Each cut is moved forward to the next char boundary accepted by `is_boundary`, so a codepoint is never split
+fn split_points(text: &str, parts: usize, is_boundary: impl Fn(usize) -> bool) -> Vec<usize> {
+    let mut points = vec![0];
+    for part in 1..parts.max(1) {
+        let mut i = (text.len() * part / parts).max(*points.last().unwrap());
+        while i < text.len() && !(text.is_char_boundary(i) && is_boundary(i)) {
+            i += 1;
+        }
+        if i > *points.last().unwrap() && i < text.len() {
+            points.push(i);
+        }
+    }
+    points.push(text.len());
+    points
+}
+
+// Non-empty matches of `regex` in `text`, with the text between two matches as a chunk of its own. Empty matches
+// make the search step over a char, so this also keeps what patterns like `\b` would otherwise drop
+fn regex_chunks<'a>(regex: &Regex, text: &'a str) -> Vec<&'a str> {
+    let mut chunks = Vec::new();
+    let mut end = 0;
+    for m in regex.find_iter(text) {
+        let m = m.expect("Regex failed while splitting the text");
+        if m.start() > end {
+            chunks.push(&text[end..m.start()]);
+        }
+        if !m.as_str().is_empty() {
+            chunks.push(m.as_str());
+        }
+        end = end.max(m.end());
+    }
+    if end < text.len() {
+        chunks.push(&text[end..]);
+    }
+    chunks
+}
+
+// Bigrams are counted inside each chunk, never across two of them
+fn seq_get_stats(chunks: &[Vec<u32>]) -> Stats {
+    let mut stats = HashMap::new();
+    for chunk in chunks {
+        get_stats(chunk, &mut stats);
+    }
+    stats
+}
+
+// Each rayon worker counts its own share of the bigrams, then the partial maps are summed
+fn par_get_stats(chunks: &[Vec<u32>]) -> Stats {
+    chunks.par_iter()
+        .flat_map(|chunk| chunk.par_windows(2))
+        .fold(HashMap::new, |mut stats, window| {
+            *stats.entry((window[0], window[1])).or_insert(0) += 1;
+            stats
+        })
+        .reduce(HashMap::new, |mut a, b| {
+            for (pair, count) in b {
+                *a.entry(pair).or_insert(0) += count;
+            }
+            a
+        })
+}
+
+// Most frequent bigram, ties go to the smallest pair so that training is deterministic
+fn top_pair(stats: &Stats) -> Option<(u32, u32)> {
+    stats.iter()
+        .max_by(|(p1, c1), (p2, c2)| c1.cmp(c2).then(p2.cmp(p1)))
+        .map(|(&pair, _)| pair)
+}
+
+impl Tokenize for BasicTokenizer {
+    fn train(&mut self, text: &str, vocab_size: usize, verbose: bool) {
+        self.train_loop(text, vocab_size, seq_get_stats, &mut reporter(verbose, self.progress_format));
+    }
+
+    fn encode(&self, text: &str) -> Vec<u32> {
+        self.try_encode(text).expect("Unknown char with UnknownPolicy::Error, use try_encode")
+    }
+
+    fn decode(&self, ids: &[u32]) -> Result<String, DecodeError> {
+        self.decode_with(ids, false)
+    }
+
+    fn save(&self, vocab_path: &str, merges_path: &str) -> std::io::Result<()> {
+        let mut vocab = BufWriter::new(std::fs::File::create(vocab_path)?);
+        let mut merges = BufWriter::new(std::fs::File::create(merges_path)?);
+        self.save_to(&mut vocab, &mut merges)?;
+        vocab.flush()?;
+        merges.flush()
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab.len()
+    }
+}
+
+
+pub const GPT4_SPLIT_PATTERN: &str = concat!(
+    r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}+|\p{N}{1,3}|",
+    r" ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+(?!\S)|\s+"
+);
+
+pub struct RegexTokenizer {
+    inner: BasicTokenizer,
+    regex: Regex,
+    boundary_sentinel: u32,
+    max_chunk_len: usize
+}
+
+/// Default of `RegexTokenizer::with_max_chunk_len`, far above any word while keeping encode time bounded.
+pub const DEFAULT_MAX_CHUNK_LEN: usize = 4096;
+
+impl RegexTokenizer {
+    /// Fails on patterns that match the empty string, see `allowing_empty_matches` to accept them.
+    /// Patterns that only match empty in context, like `\b`, pass this check and split as described there.
+    pub fn new(pattern: &str) -> Result<Self, PatternError> {
+        let tokenizer = Self::allowing_empty_matches(pattern)?;
+        if tokenizer.regex.is_match("")? {
+            return Err(PatternError::MatchesEmpty)
+        }
+        Ok(tokenizer)
+    }
+
+    /// Same as `new` for patterns that may match the empty string, e.g. `\w*`. Empty matches are skipped
+    /// when splitting, and the search resumes one char further, so they never produce an empty chunk.
+    /// No input is lost: text the search steps over or no match covers becomes a chunk of its own.
+    pub fn allowing_empty_matches(pattern: &str) -> Result<Self, fancy_regex::Error> {
+        Ok(RegexTokenizer {
+            inner: BasicTokenizer::new(),
+            regex: Regex::new(pattern)?,
+            boundary_sentinel: u32::MAX,
+            max_chunk_len: DEFAULT_MAX_CHUNK_LEN
+        })
+    }
+
+    /// Cap in bytes on the length of a regex chunk, `DEFAULT_MAX_CHUNK_LEN` by default. Encoding a chunk
+    /// is quadratic in its length, so a pathological pattern or input (e.g. megabytes of whitespace) could hang;
+    /// longer chunks are cut into pieces of at most `max` bytes, on char boundaries, for training and encoding alike.
+    pub fn with_max_chunk_len(mut self, max: usize) -> Self {
+        self.max_chunk_len = max.max(1);
+        self
+    }
+
+    /// Registers special tokens on the inner tokenizer, see `BasicTokenizer::register_special_tokens`.
+    pub fn register_special_tokens(&mut self, tokens: &[(&str, u32)]) {
+        self.inner.register_special_tokens(tokens);
+    }
+
+    /// See `BasicTokenizer::with_special_matching`. Specials are looked for before the text is split
+    /// by the pattern, so a special is never cut across two chunks.
+    pub fn with_special_matching(mut self, matching: SpecialMatching) -> Self {
+        self.inner.special_matching = matching;
+        self
+    }
+
+    /// Id `encode_with_boundaries` puts between chunks, `u32::MAX` by default.
+    pub fn with_boundary_sentinel(mut self, id: u32) -> Self {
+        self.boundary_sentinel = id;
+        self
+    }
+
+    /// Like `encode`, with the boundary sentinel inserted between the ids of two regex chunks,
+    /// to see where the pre-tokenization cut the text. The output is for debugging, not for decoding.
+    pub fn encode_with_boundaries(&self, text: &str) -> Vec<u32> {
+        let mut ids = Vec::with_capacity(text.len());
+        for (i, segment) in self.segments(text).into_iter().enumerate() {
+            if i > 0 { ids.push(self.boundary_sentinel) }
+            match segment {
+                Segment::Special(id) => ids.push(id),
+                Segment::Piece(chunk) => ids.extend(self.inner.encode(&chunk)),
+            }
+        }
+        ids
+    }
+
+    // Special tokens found in `text` and the regex chunks of the text between them, in order
+    fn segments<'a>(&self, text: &'a str) -> Vec<Segment<'a>> {
+        let mut segments = Vec::new();
+        for (special, range) in self.inner.split_specials(text) {
+            match special {
+                Some(id) => segments.push(Segment::Special(id)),
+                None => segments.extend(self.split(&text[range]).into_iter().map(|chunk| Segment::Piece(chunk.into()))),
+            }
+        }
+        segments
+    }
+
+    // Chunks of text the merges are confined to
+    fn split<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        let mut chunks = Vec::new();
+        for mut chunk in regex_chunks(&self.regex, text) {
+            while chunk.len() > self.max_chunk_len {
+                // Back to the previous char boundary, or forward past a char longer than the cap
+                let mut end = self.max_chunk_len;
+                while !chunk.is_char_boundary(end) { end -= 1 }
+                if end == 0 {
+                    end = chunk.chars().next().map_or(0, char::len_utf8);
+                }
+                chunks.push(&chunk[..end]);
+                chunk = &chunk[end..];
+            }
+            chunks.push(chunk);
+        }
+        chunks
+    }
+}
+
+impl Tokenize for RegexTokenizer {
+    fn train(&mut self, text: &str, vocab_size: usize, verbose: bool) {
+        let chunks = self.split(text);
+        let format = self.inner.progress_format;
+        self.inner.train_pieces(&chunks, Some(vocab_size), 1, seq_get_stats, &mut reporter(verbose, format));
+    }
+
+    fn encode(&self, text: &str) -> Vec<u32> {
+        let mut ids = Vec::with_capacity(text.len());
+        for segment in self.segments(text) {
+            match segment {
+                Segment::Special(id) => ids.push(id),
+                Segment::Piece(chunk) => ids.extend(self.inner.encode(&chunk)),
+            }
+        }
+        ids
+    }
+
+    fn decode(&self, ids: &[u32]) -> Result<String, DecodeError> {
+        self.inner.decode(ids)
+    }
+
+    fn save(&self, vocab_path: &str, merges_path: &str) -> std::io::Result<()> {
+        self.inner.save(vocab_path, merges_path)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.inner.vocab_size()
+    }
+}
+
+/// Encodes each regex chunk with the first of its tokenizers that handles it, e.g. a tokenizer specialized
+/// on code or numbers in front of a general one. A tokenizer handles a chunk when it encodes it in fewer ids
+/// than the chunk has bytes, i.e. when at least one of its merges applies; the last one handles whatever is left.
+///
+/// The id spaces are stacked in chain order: ids of the first tokenizer are kept as they are, ids of the next one
+/// are shifted by the `vocab_size` of the first, and so on. `decode` maps every id back to its tokenizer by
+/// that range, then decodes each run of ids of the same tokenizer with it and concatenates the texts.
+/// This expects the ids of each tokenizer to lie in `0..vocab_size`, so special tokens are not supported.
+pub struct ChainTokenizer {
+    tokenizers: Vec<Box<dyn Tokenize>>,
+    regex: Regex,
+}
+
+impl ChainTokenizer {
+    /// Fails if `tokenizers` is empty. Patterns are checked like in `RegexTokenizer::new`.
+    pub fn new(pattern: &str, tokenizers: Vec<Box<dyn Tokenize>>) -> Result<Self, ChainError> {
+        if tokenizers.is_empty() {
+            return Err(ChainError::Empty)
+        }
+        let regex = Regex::new(pattern).map_err(PatternError::from)?;
+        if regex.is_match("").map_err(PatternError::from)? {
+            return Err(PatternError::MatchesEmpty.into())
+        }
+        Ok(ChainTokenizer { tokenizers, regex })
+    }
+
+    // First id of each tokenizer in the stacked id space
+    fn offsets(&self) -> Vec<u32> {
+        let mut offset = 0;
+        self.tokenizers.iter().map(|tokenizer| {
+            let start = offset;
+            offset += tokenizer.vocab_size() as u32;
+            start
+        }).collect()
+    }
+}
+
+impl Tokenize for ChainTokenizer {
+    /// Trains the last tokenizer only, the general fallback. The ones in front are expected to be trained
+    /// on their own domain before being chained.
+    fn train(&mut self, text: &str, vocab_size: usize, verbose: bool) {
+        self.tokenizers.last_mut().expect("Chain is never empty").train(text, vocab_size, verbose);
+    }
+
+    fn encode(&self, text: &str) -> Vec<u32> {
+        let offsets = self.offsets();
+        let last = self.tokenizers.len() - 1;
+        let mut ids = Vec::with_capacity(text.len());
+        for chunk in regex_chunks(&self.regex, text) {
+            for (i, tokenizer) in self.tokenizers.iter().enumerate() {
+                let chunk_ids = tokenizer.encode(chunk);
+                if chunk_ids.len() < chunk.len() || i == last {
+                    ids.extend(chunk_ids.into_iter().map(|id| id + offsets[i]));
+                    break
+                }
+            }
+        }
+        ids
+    }
+
+    fn decode(&self, ids: &[u32]) -> Result<String, DecodeError> {
+        let offsets = self.offsets();
+        let end = offsets[offsets.len() - 1] + self.tokenizers[offsets.len() - 1].vocab_size() as u32;
+        let owner = |id: u32| offsets.iter().rposition(|&offset| offset <= id).filter(|_| id < end);
+
+        let mut text = String::new();
+        let mut start = 0;
+        while start < ids.len() {
+            let i = owner(ids[start]).ok_or(DecodeError::UnknownId(ids[start]))?;
+            let mut stop = start + 1;
+            while stop < ids.len() && owner(ids[stop]) == Some(i) { stop += 1 }
+            let run: Vec<u32> = ids[start..stop].iter().map(|id| id - offsets[i]).collect();
+            text.push_str(&self.tokenizers[i].decode(&run)?);
+            start = stop;
+        }
+        Ok(text)
+    }
+
+    /// Saves each tokenizer with its position in the chain appended to both paths, e.g. `merges.txt.0`.
+    fn save(&self, vocab_path: &str, merges_path: &str) -> std::io::Result<()> {
+        for (i, tokenizer) in self.tokenizers.iter().enumerate() {
+            tokenizer.save(&format!("{vocab_path}.{i}"), &format!("{merges_path}.{i}"))?;
+        }
+        Ok(())
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.tokenizers.iter().map(|tokenizer| tokenizer.vocab_size()).sum()
+    }
+}
+
+/// Token counts of both tokenizers on the same corpus. Ratios are ids per byte, as in `train_to_ratio`,
+/// so lower means better compression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    pub bytes: usize,
+    pub basic_tokens: usize,
+    pub regex_tokens: usize,
+    pub basic_ratio: f64,
+    pub regex_ratio: f64,
+}
+
+/// Encodes `corpus` with both tokenizers to help choose between them.
+pub fn compare_tokenizers(basic: &BasicTokenizer, regex: &RegexTokenizer, corpus: &str) -> Comparison {
+    let bytes = corpus.len();
+    let basic_tokens = basic.encode(corpus).len();
+    let regex_tokens = regex.encode(corpus).len();
+    Comparison {
+        bytes,
+        basic_tokens,
+        regex_tokens,
+        basic_ratio: basic_tokens as f64 / bytes.max(1) as f64,
+        regex_ratio: regex_tokens as f64 / bytes.max(1) as f64,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let merged = merge(&[1, 2, 3, 4, 4, 4, 5, 6], (3, 4), 10);
+        assert_eq!(merged, [1, 2, 10, 4, 4, 5, 6]);
+
+        // A pair at the very end is not repeated, inputs too short to hold a pair are kept as they are
+        assert_eq!(merge(&[1, 2, 3, 4], (3, 4), 10), [1, 2, 10]);
+        assert_eq!(merge(&[3, 4], (3, 4), 10), [10]);
+        assert_eq!(merge(&[3], (3, 4), 10), [3]);
+        assert_eq!(merge(&[], (3, 4), 10), []);
+
+        // Pair at the start, several times and not at all
+        assert_eq!(merge(&[3, 4, 1, 2], (3, 4), 10), [10, 1, 2]);
+        assert_eq!(merge(&[3, 4, 1, 3, 4, 2, 3, 4], (3, 4), 10), [10, 1, 10, 2, 10]);
+        assert_eq!(merge(&[1, 2, 3, 5], (3, 4), 10), [1, 2, 3, 5]);
+        // Overlapping occurrences merge left to right
+        assert_eq!(merge(&[1, 1, 1], (1, 1), 10), [10, 1]);
+    }
+
+    #[test]
+    fn test_train() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 500, false);
+    }
+
+    #[test] 
+    fn test_encode() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 500, false);
+        println!("{:?}", tokenizer.encode("Self driving is the future! 🙄"));
+    }
+
+    #[test] 
+    fn test_decode() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 500, false);
+        let s = "Self driving is the future! 🙄";
+
+        let encoded = tokenizer.encode(s);
+        assert_eq!(s, tokenizer.decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn test_train_with_pool() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+        let (small, large) = std::thread::scope(|s| {
+            let small = s.spawn(|| {
+                let mut tokenizer = BasicTokenizer::new();
+                tokenizer.train_with_pool(&text, 300, false, &pool);
+                tokenizer
+            });
+            let large = s.spawn(|| {
+                let mut tokenizer = BasicTokenizer::new();
+                tokenizer.train_with_pool(&text, 400, false, &pool);
+                tokenizer
+            });
+            (small.join().unwrap(), large.join().unwrap())
+        });
+
+        let mut expected = BasicTokenizer::new();
+        expected.train(&text, 300, false);
+        assert_eq!(small.merges, expected.merges);
+        assert_eq!(large.merges.len(), 400 - 256);
+    }
+
+    #[test]
+    fn test_base_bytes_of() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        assert_eq!(tokenizer.base_bytes_of(b'a' as u32), [b'a']);
+        for &minted in tokenizer.merges.values() {
+            let bytes = tokenizer.base_bytes_of(minted);
+            assert!(bytes.len() > 1);
+            assert_eq!(bytes, tokenizer.vocab[&minted].as_ref());
+        }
+    }
+
+    #[test]
+    fn test_char_level_byte_fallback() {
+        let text = "café crème brûlée, café au lait";
+        let mut tokenizer = BasicTokenizer::new().with_alphabet(Alphabet::Chars);
+        tokenizer.train(text, 280, false);
+
+        // 'é' was seen during training so it is a single token, 'ñ' is not and falls back to its bytes
+        assert_eq!(tokenizer.encode("é"), [tokenizer.char_ids[&'é']]);
+        assert_eq!(tokenizer.encode("ñ"), [0xC3, 0xB1]);
+
+        let s = "señor café";
+        assert_eq!(tokenizer.decode(&tokenizer.encode(s)).unwrap(), s);
+    }
+
+    #[test]
+    fn test_char_level_unknown_policies() {
+        let text = "café crème brûlée, café au lait";
+        let mut tokenizer = BasicTokenizer::new()
+            .with_alphabet(Alphabet::Chars)
+            .with_unknown_policy(UnknownPolicy::Error);
+        tokenizer.train(text, 280, false);
+        assert_eq!(tokenizer.try_encode("ñ"), Err(EncodeError::UnknownChar('ñ')));
+
+        let tokenizer = tokenizer.with_unknown_policy(UnknownPolicy::Unk(0));
+        assert_eq!(tokenizer.encode("ñ"), [0]);
+    }
+
+    #[test]
+    fn test_char_level_vocab_below_alphabet() {
+        // The char tokens alone exceed the requested size, training mints nothing instead of underflowing
+        let mut tokenizer = BasicTokenizer::new().with_alphabet(Alphabet::Chars);
+        tokenizer.train("café crème", 256, false);
+        assert!(tokenizer.merges.is_empty());
+        assert_eq!(tokenizer.vocab.len(), 256 + 2);
+    }
+
+    #[test]
+    fn test_continue_training_skips_existing_pairs() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        // Raw bytes instead of encoded ids: every known merge shows up again as the top candidate
+        let raw_ids: Vec<u32> = text.as_bytes().iter().map(|&b| b as u32).collect();
+        tokenizer.learn_merges(vec![raw_ids], Some(1), 1, seq_get_stats, &mut |_| ControlFlow::Continue(()));
+
+        let mut expected = BasicTokenizer::new();
+        expected.train(&text, 301, false);
+        assert_eq!(tokenizer.merges, expected.merges);
+        assert_eq!(tokenizer.vocab.len(), 301);
+    }
+
+    #[test]
+    fn test_continue_training() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        tokenizer.continue_training(&text, 20, false);
+
+        let mut expected = BasicTokenizer::new();
+        expected.train(&text, 320, false);
+        assert_eq!(tokenizer.merges, expected.merges);
+    }
+
+    #[test]
+    fn test_trained() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let tokenizer = BasicTokenizer::trained(&text, 300).unwrap();
+
+        let mut expected = BasicTokenizer::new();
+        expected.train(&text, 300, false);
+        assert_eq!(tokenizer.vocab, expected.vocab);
+        assert_eq!(tokenizer.merges, expected.merges);
+
+        assert_eq!(
+            BasicTokenizer::trained(&text, 100).err(),
+            Some(TokenizerError::VocabSizeTooSmall { vocab_size: 100, min: 256 })
+        );
+    }
+
+    #[test]
+    fn test_contiguous_ids_after_prune() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        let pair = *tokenizer.merges.iter().find(|&(_, &idx)| idx == 270).unwrap().0;
+        tokenizer.prune(&[pair]);
+
+        let vocab_size = tokenizer.vocab.len() as u32;
+        assert!(vocab_size < 300);
+        assert_eq!(tokenizer.contiguous_ids(), (0..vocab_size).collect::<Vec<u32>>());
+
+        for &idx in tokenizer.merges.values() {
+            assert_eq!(tokenizer.base_bytes_of(idx), tokenizer.vocab[&idx].as_ref());
+        }
+        let s = "Self driving is the future! 🙄";
+        assert_eq!(tokenizer.decode(&tokenizer.encode(s)).unwrap(), s);
+    }
+
+    #[test]
+    fn test_prune_remaps_ids_outside_vocab() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new().with_bos(300).with_eos(100_001);
+        tokenizer.register_special_tokens(&[("<|bos|>", 300), ("<|endoftext|>", 100_000), ("<|eos|>", 100_001)]);
+        tokenizer.train(&text, 300, false);
+        let pair_of = |tokenizer: &BasicTokenizer, idx: u32| *tokenizer.merges.iter().find(|&(_, &i)| i == idx).unwrap().0;
+        let (first, pair) = (pair_of(&tokenizer, 256), pair_of(&tokenizer, 270));
+        let dropped = tokenizer.merges_from(pair);
+        let kept = (271..300).map(|idx| pair_of(&tokenizer, idx)).find(|p| !dropped.contains(p)).unwrap();
+        let kept_bytes = tokenizer.vocab[&tokenizer.merges[&kept]].clone();
+        tokenizer.prioritize_pairs(&[first, kept, pair]);
+        tokenizer.prune(&[pair]);
+
+        // The specials follow the vocab in their order, BOS and EOS still point at theirs
+        let vocab_size = tokenizer.vocab.len() as u32;
+        assert_eq!(tokenizer.contiguous_ids(), (0..vocab_size).collect::<Vec<u32>>());
+        assert_eq!(tokenizer.special_tokens["<|bos|>"], vocab_size);
+        assert_eq!(tokenizer.special_tokens["<|endoftext|>"], vocab_size + 1);
+        assert_eq!(tokenizer.special_tokens["<|eos|>"], vocab_size + 2);
+        assert_eq!(tokenizer.inverse_special_tokens[&(vocab_size + 1)], "<|endoftext|>");
+        let ids = tokenizer.encode("Self driving");
+        assert_eq!((ids[0], ids[ids.len() - 1]), (vocab_size, vocab_size + 2));
+        assert_eq!(tokenizer.decode_skip_special(&ids).unwrap(), "Self driving");
+
+        // A byte pair keeps its ids, a kept merge is renumbered with its halves, the pruned one is dropped
+        assert_eq!(tokenizer.priority_pairs.len(), 2);
+        assert_eq!(tokenizer.priority_pairs[0], first);
+        assert_eq!(tokenizer.vocab[&tokenizer.merges[&tokenizer.priority_pairs[1]]], kept_bytes);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        let projected = BasicTokenizer::projected_footprint(text.len(), 100_000);
+        assert_eq!(
+            tokenizer.try_train(&text, 100_000, Some(64 * 1024), false),
+            Err(TokenizerError::MemoryLimitExceeded { projected, limit: 64 * 1024 })
+        );
+        assert!(tokenizer.vocab.is_empty());
+
+        tokenizer.try_train(&text, 300, Some(1024 * 1024), false).unwrap();
+        assert!(tokenizer.memory_footprint() > 0);
+    }
+
+    #[test]
+    fn test_decode_skip_special() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        tokenizer.register_special_tokens(&[("<|endoftext|>", 300), ("<|pad|>", 301)]);
+
+        let mut ids = vec![301];
+        ids.extend(tokenizer.encode("Self driving"));
+        ids.push(300);
+        ids.extend(tokenizer.encode(" is the future!"));
+        ids.push(300);
+
+        assert_eq!(tokenizer.decode(&ids).unwrap(), "<|pad|>Self driving<|endoftext|> is the future!<|endoftext|>");
+        assert_eq!(tokenizer.decode_skip_special(&ids).unwrap(), "Self driving is the future!");
+        assert_eq!(tokenizer.decode_skip_special(&[302]), Err(DecodeError::UnknownId(302)));
+    }
+
+    #[test]
+    fn test_encode_with_dropout_seed() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 400, false);
+        let s = &text[..500];
+
+        let a = tokenizer.encode_with_dropout(s, 0.3, 42);
+        assert_eq!(a, tokenizer.encode_with_dropout(s, 0.3, 42));
+        assert_ne!(a, tokenizer.encode_with_dropout(s, 0.3, 7));
+        assert_eq!(tokenizer.decode(&a).unwrap(), s);
+        assert_eq!(tokenizer.encode_with_dropout(s, 0.0, 42), tokenizer.encode(s));
+    }
+
+    #[test]
+    fn test_train_with_progress_stats() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        let mut first = None;
+        let mut steps = 0;
+        tokenizer.train_with_progress(&text, 300, |progress| {
+            if progress.step == 1 {
+                first = Some((progress.pair, progress.frequency, progress.stats.clone()));
+            }
+            steps = progress.step;
+        });
+
+        let (pair, frequency, stats) = first.unwrap();
+        assert_eq!(steps, 300 - 256);
+        assert_eq!(stats[&pair], frequency);
+        assert_eq!(stats.values().max(), Some(&frequency));
+        assert_eq!(tokenizer.merges[&pair], 256);
+    }
+
+    #[test]
+    fn test_merge_runs_equivalence() {
+        let mut text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        text.push_str(&"a".repeat(300));
+        text.push_str(&"    if x:\n        return y\n".repeat(20));
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 400, false);
+
+        let inputs = [
+            "a".repeat(1001),
+            " ".repeat(64) + "x",
+            "aaab".repeat(50) + &"ba".repeat(50),
+            "        return y\n".repeat(30),
+            text.clone(),
+        ];
+        for s in &inputs {
+            let ids = tokenizer.initial_ids(s).unwrap();
+            let rank = |pair| tokenizer.merges.get(&pair).copied();
+            let expected = merge_ids(ids.clone(), &rank);
+            assert_eq!(merge_runs(run_lengths(&ids), &rank), expected);
+            assert_eq!(tokenizer.encode(s), expected);
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        assert_eq!(tokenizer.decode(&[1000]), Err(DecodeError::UnknownId(1000)));
+        assert!(matches!(tokenizer.decode(&[0xff]), Err(DecodeError::InvalidUtf8(_))));
+
+        let regex_tokenizer = RegexTokenizer::new(r"\w+|\s+|[^\w\s]+").unwrap();
+        assert_eq!(regex_tokenizer.decode(&[1000]), Err(DecodeError::UnknownId(1000)));
+    }
+
+    #[test]
+    fn test_word_marker() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new().with_word_marker('▁');
+        tokenizer.train(&text, 400, false);
+
+        let s = "Self driving is the future of transportation";
+        let ids = tokenizer.encode(s);
+        let tokens: Vec<String> = ids.iter().map(|id| String::from_utf8_lossy(&tokenizer.vocab[id]).into_owned()).collect();
+
+        // The marker can only sit at the start of a token, tokens without it continue the previous word
+        assert!(tokens.iter().all(|t| !t.chars().skip(1).any(|c| c == '▁')));
+        assert!(tokens.iter().any(|t| t.starts_with('▁')));
+        assert!(tokens.iter().skip(1).any(|t| !t.starts_with('▁')));
+        assert_eq!(tokens.iter().filter(|t| t.starts_with('▁')).count(), s.matches(' ').count());
+
+        assert_eq!(tokenizer.decode(&ids).unwrap(), s);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        tokenizer.vocab.reserve(10_000);
+        tokenizer.merges.reserve(10_000);
+        let (vocab_capacity, merges_capacity) = (tokenizer.vocab.capacity(), tokenizer.merges.capacity());
+        let footprint = tokenizer.memory_footprint();
+
+        tokenizer.shrink_to_fit();
+        assert!(tokenizer.vocab.capacity() < vocab_capacity);
+        assert!(tokenizer.merges.capacity() < merges_capacity);
+        assert!(tokenizer.vocab.capacity() >= tokenizer.vocab.len());
+        assert!(tokenizer.memory_footprint() < footprint);
+    }
+
+    #[test]
+    fn test_bos_eos() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        tokenizer.register_special_tokens(&[("<|bos|>", 300), ("<|eos|>", 301)]);
+        let plain = tokenizer.encode("the");
+
+        let tokenizer = tokenizer.with_bos(300).with_eos(301);
+        let ids = tokenizer.encode("the");
+        assert_eq!(ids.first(), Some(&300));
+        assert_eq!(ids.last(), Some(&301));
+        assert_eq!(ids[1..ids.len() - 1], plain);
+        assert_eq!(tokenizer.encode(""), [300, 301]);
+        assert_eq!(tokenizer.decode(&ids).unwrap(), "<|bos|>the<|eos|>");
+        assert_eq!(tokenizer.decode_skip_special(&ids).unwrap(), "the");
+    }
+
+    // Behavior every `Tokenize` implementation must share, called with an already trained tokenizer
+    fn check_contract(tokenizer: &impl Tokenize) {
+        for s in ["Self driving is the future! 🙄", "  two  spaces\n\nand newlines", "x"] {
+            assert_eq!(tokenizer.decode(&tokenizer.encode(s)).unwrap(), s);
+        }
+
+        assert!(tokenizer.encode("").is_empty());
+        assert_eq!(tokenizer.decode(&[]).unwrap(), "");
+
+        assert_eq!(tokenizer.encode("a"), [b'a' as u32]);
+        assert_eq!(tokenizer.decode(&[b'a' as u32]).unwrap(), "a");
+
+        let s = "the quick brown fox jumps over the lazy dog";
+        assert_eq!(tokenizer.encode(s), tokenizer.encode(s));
+    }
+
+    #[test]
+    fn test_contract() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+
+        let mut basic = BasicTokenizer::new();
+        basic.train(&text, 300, false);
+        check_contract(&basic);
+
+        let mut regex = RegexTokenizer::new(GPT4_SPLIT_PATTERN).unwrap();
+        regex.train(&text, 300, false);
+        check_contract(&regex);
+    }
+
+    #[test]
+    fn test_train_to_ratio() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        let ratio = tokenizer.train_to_ratio(&text, 0.6, false);
+
+        let encoded_ratio = tokenizer.encode(&text).len() as f64 / text.len() as f64;
+        assert_eq!(ratio, encoded_ratio);
+        assert!(ratio <= 0.6 && ratio > 0.59, "ratio {ratio}");
+    }
+
+    #[test]
+    fn test_train_degenerate_corpus() {
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train("aaaaaaaa", 300, false);
+
+        // 8 -> 4 -> 2 -> 1 ids, after that there is no pair left to merge
+        let expected: HashMap<(u32, u32), u32> = [((97, 97), 256), ((256, 256), 257), ((257, 257), 258)].into();
+        assert_eq!(tokenizer.merges, expected);
+        assert_eq!(tokenizer.encode("aaaaaaaa"), [258]);
+        assert_eq!(tokenizer.decode(&tokenizer.encode("aaaaaaaaa")).unwrap(), "aaaaaaaaa");
+
+        for text in ["", "a"] {
+            let mut tokenizer = BasicTokenizer::new();
+            tokenizer.train(text, 300, false);
+            assert!(tokenizer.merges.is_empty());
+            assert_eq!(tokenizer.vocab.len(), 256);
+        }
+    }
+
+    #[test]
+    fn test_encode_with_rank_limit() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 400, false);
+        let s = &text[..1000];
+
+        let full = tokenizer.encode_with_rank_limit(s, 399);
+        let limited = tokenizer.encode_with_rank_limit(s, 300);
+        assert_eq!(full, tokenizer.encode(s));
+        assert!(limited.len() > full.len());
+        assert!(limited.iter().all(|&id| id <= 300));
+        assert_eq!(tokenizer.encode_with_rank_limit(s, 255).len(), s.len());
+
+        let mut smaller = BasicTokenizer::new();
+        smaller.train(&text, 301, false);
+        assert_eq!(limited, smaller.encode(s));
+    }
+
+    #[test]
+    fn test_encode_flat_equivalence() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 400, false);
+
+        for s in [text.as_str(), "Self driving is the future! 🙄", "", "    aaaa    "] {
+            assert_eq!(tokenizer.encode_flat(s), tokenizer.encode(s));
+        }
+
+        let pair = *tokenizer.merges.iter().find(|&(_, &idx)| idx == 300).unwrap().0;
+        tokenizer.prune(&[pair]);
+        assert_eq!(tokenizer.encode_flat(&text), tokenizer.encode(&text));
+    }
+
+    #[test]
+    fn test_encode_to_file() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        let ids = tokenizer.encode(&text);
+
+        let dir = std::env::temp_dir();
+        for (width, name) in [(IdWidth::U16, "toktokenizer_ids_u16.bin"), (IdWidth::U32, "toktokenizer_ids_u32.bin")] {
+            let path = dir.join(name);
+            let path = path.to_str().unwrap();
+            tokenizer.encode_to_file(&text, path, width).unwrap();
+            assert_eq!(read_ids(path, width).unwrap(), ids);
+            std::fs::remove_file(path).unwrap();
+        }
+
+        // 70000 does not fit in 16 bits
+        tokenizer.register_special_tokens(&[("<|big|>", 70000)]);
+        let tokenizer = tokenizer.with_eos(70000);
+        let path = dir.join("toktokenizer_ids_overflow.bin");
+        let err = tokenizer.encode_to_file("hi", path.to_str().unwrap(), IdWidth::U16).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "id 70000 does not fit in 16 bits");
+        assert!(!path.exists());
+
+        // Chars outside of a strict alphabet are reported the same way instead of panicking
+        let mut strict = BasicTokenizer::new().with_alphabet(Alphabet::Chars).with_unknown_policy(UnknownPolicy::Error);
+        strict.train("abc", 256, false);
+        let err = strict.encode_to_file("é", path.to_str().unwrap(), IdWidth::U32).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_progress_format_lowercase() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let format = ProgressFormat { lowercase: true, ..Default::default() };
+        let mut tokenizer = BasicTokenizer::new().with_progress_format(format);
+        let mut lines = HashMap::new();
+        tokenizer.train_with_progress(&text, 500, |progress| {
+            lines.insert(progress.minted_idx, format.format(progress));
+        });
+
+        let (&idx, token) = tokenizer.vocab.iter()
+            .find(|(_, token)| token.len() > 1 && token.iter().any(u8::is_ascii_uppercase))
+            .unwrap();
+        let token = String::from_utf8_lossy(token).into_owned();
+        assert!(lines[&idx].ends_with(&format!("({})", token.to_lowercase())));
+        assert_ne!(token, token.to_lowercase());
+    }
+
+    #[test]
+    fn test_train_with_replay() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let path = std::env::temp_dir().join("toktokenizer_replay.txt");
+        let path = path.to_str().unwrap();
+
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train_with_replay(&text, 300, false, path).unwrap();
+        let replayed = BasicTokenizer::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(replayed.merges, tokenizer.merges);
+        assert_eq!(replayed.vocab, tokenizer.vocab);
+        assert_eq!(replayed.encode(&text), tokenizer.encode(&text));
+    }
+
+    #[test]
+    fn test_encode_optimal() {
+        let (a, b, c, d) = (b'a' as u32, b'b' as u32, b'c' as u32, b'd' as u32);
+        // Greedy applies "ab" first and can no longer use "bcd"
+        let tokenizer = BasicTokenizer::replay_merges(vec![((a, b), 256), ((b, c), 257), ((257, d), 258)]).unwrap();
+
+        assert_eq!(tokenizer.encode("abcd"), [256, c, d]);
+        assert_eq!(tokenizer.encode_optimal("abcd"), [a, 258]);
+        assert_eq!(tokenizer.decode(&tokenizer.encode_optimal("abcd")).unwrap(), "abcd");
+
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        let optimal = tokenizer.encode_optimal(&text);
+        assert!(optimal.len() <= tokenizer.encode(&text).len());
+        assert_eq!(tokenizer.decode(&optimal).unwrap(), text);
+    }
+
+    #[test]
+    fn test_encode_optimal_without_vocab() {
+        // Nothing trained, every byte is its own id like with `encode`
+        let untrained = BasicTokenizer::new();
+        assert_eq!(untrained.encode_optimal("héllo 🙄"), untrained.encode("héllo 🙄"));
+        assert!(untrained.encode_optimal("").is_empty());
+
+        // Chars outside of a char-level alphabet follow the policy instead of falling back to byte tokens
+        let mut chars = BasicTokenizer::new().with_alphabet(Alphabet::Chars).with_unknown_policy(UnknownPolicy::Unk(0));
+        chars.train("abc abc", 260, false);
+        assert_eq!(chars.encode_optimal("aéb"), chars.encode("aéb"));
+        assert_eq!(chars.encode_optimal("aéb")[1], 0);
+        let chars = chars.with_unknown_policy(UnknownPolicy::ByteFallback);
+        assert_eq!(chars.encode_optimal("aéb"), chars.encode("aéb"));
+    }
+
+    #[test]
+    #[should_panic(expected = "UnknownPolicy::Error")]
+    fn test_encode_optimal_unknown_char() {
+        let mut strict = BasicTokenizer::new().with_alphabet(Alphabet::Chars).with_unknown_policy(UnknownPolicy::Error);
+        strict.train("abc abc", 260, false);
+        strict.encode_optimal("aéb");
+    }
+
+    #[test]
+    fn test_token_display() {
+        let (a, b) = (b'A' as u32, b'B' as u32);
+        let mut tokenizer = BasicTokenizer::replay_merges(vec![((a, b), 256)]).unwrap();
+        tokenizer.register_special_tokens(&[("<|endoftext|>", 257)]);
+
+        assert_eq!(tokenizer.token(256).unwrap().to_string(), r#"id=256 bytes=[65,66] text="AB""#);
+        assert!(tokenizer.token(257).unwrap().to_string().contains("<|endoftext|>"));
+        assert!(tokenizer.token(0xff).unwrap().to_string().contains('\u{fffd}'));
+        assert_eq!(tokenizer.token(258), None);
+
+        // A special sharing an id with the vocab shadows it, as when decoding
+        tokenizer.register_special_tokens(&[("<|pad|>", 256)]);
+        assert_eq!(tokenizer.token(256).unwrap().bytes, tokenizer.token_bytes(&256).unwrap());
+        assert_eq!(tokenizer.token(256).unwrap().bytes, b"<|pad|>");
+    }
+
+    #[test]
+    fn test_token_entropy() {
+        let tokenizer = BasicTokenizer::new();
+        // Untrained: every byte is its own token, 4 equally frequent ids give 2 bits
+        assert!((tokenizer.token_entropy("abcdabcd") - 2.0).abs() < 1e-12);
+        assert_eq!(tokenizer.token_entropy("aaaa"), 0.0);
+        assert_eq!(tokenizer.token_entropy(""), 0.0);
+
+        // p = (1/2, 1/4, 1/4) gives 1.5 bits
+        assert!((tokenizer.token_entropy("aabc") - 1.5).abs() < 1e-12);
+        assert_eq!(tokenizer.token_frequencies("aabc")[&(b'a' as u32)], 2);
+    }
+
+    #[test]
+    fn test_prioritize_pairs() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let (q, u, i) = (b'q' as u32, b'u' as u32, b'i' as u32);
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.prioritize_pairs(&[(q, u), (256, i), (1000, 1001)]);
+        tokenizer.train(&text, 300, false);
+
+        assert_eq!(tokenizer.merges[&(q, u)], 256);
+        assert_eq!(tokenizer.merges[&(256, i)], 257);
+        assert_eq!(tokenizer.vocab[&257].as_ref(), b"qui");
+        assert_eq!(tokenizer.merges.len(), 300 - 256);
+
+        // The frequency driven merges come right after
+        let mut plain = BasicTokenizer::new();
+        plain.train(&text, 260, false);
+        let first = plain.merges.iter().find(|&(_, &idx)| idx == 256).unwrap().0;
+        assert_eq!(tokenizer.merges[first], 258);
+    }
+
+    #[test]
+    fn test_assert_single_token() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let (q, u, i) = (b'q' as u32, b'u' as u32, b'i' as u32);
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.prioritize_pairs(&[(q, u), (256, i)]);
+        tokenizer.train(&text, 300, false);
+        tokenizer.register_special_tokens(&[("<|bos|>", 300)]);
+        let tokenizer = tokenizer.with_bos(300);
+
+        assert!(tokenizer.assert_single_token("qui"));
+        assert!(tokenizer.assert_single_token("x"));
+        assert!(!tokenizer.assert_single_token("Self driving is the future!"));
+        assert!(!tokenizer.assert_single_token(""));
+    }
+
+    #[test]
+    fn test_normalized_newlines() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new().with_normalized_newlines();
+        tokenizer.train(&text.replace('\n', "\r\n"), 300, false);
+
+        let lf = "first line\nsecond line\n\nthird";
+        let crlf = "first line\r\nsecond line\r\n\r\nthird";
+        assert_eq!(tokenizer.encode(crlf), tokenizer.encode(lf));
+        assert_eq!(tokenizer.encode("a\rb"), tokenizer.encode("a\nb"));
+        assert_eq!(tokenizer.decode(&tokenizer.encode(crlf)).unwrap(), lf);
+
+        let mut lf_tokenizer = BasicTokenizer::new();
+        lf_tokenizer.train(&text.replace("\r\n", "\n"), 300, false);
+        assert_eq!(tokenizer.merges, lf_tokenizer.merges);
+    }
+
+    #[test]
+    fn test_tokens_containing() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        let ids = tokenizer.tokens_containing(b' ');
+        assert!(ids.contains(&(b' ' as u32)));
+        assert!(ids.iter().filter(|&&id| id >= 256).count() > 1);
+        assert!(ids.iter().all(|id| tokenizer.vocab[id].contains(&b' ')));
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_compare_tokenizers() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut basic = BasicTokenizer::new();
+        basic.train(&text, 300, false);
+        let mut regex = RegexTokenizer::new(GPT4_SPLIT_PATTERN).unwrap();
+        regex.train(&text, 300, false);
+
+        let corpus = &text[..text.char_indices().nth(2000).map_or(text.len(), |(i, _)| i)];
+        assert_eq!(basic.decode(&basic.encode(corpus)).unwrap(), corpus);
+        assert_eq!(regex.decode(&regex.encode(corpus)).unwrap(), corpus);
+
+        let comparison = compare_tokenizers(&basic, &regex, corpus);
+        assert_eq!(comparison.bytes, corpus.len());
+        assert_eq!(comparison.basic_tokens, basic.encode(corpus).len());
+        assert_eq!(comparison.regex_tokens, regex.encode(corpus).len());
+        assert!(comparison.basic_ratio > 0.0 && comparison.basic_ratio < 1.0);
+        assert!(comparison.regex_ratio > 0.0 && comparison.regex_ratio < 1.0);
+    }
+
+    #[test]
+    fn test_prune_unused_chars() {
+        let text = "héllo wörld, héllo wörld, héllo";
+        let mut tokenizer = BasicTokenizer::new()
+            .with_alphabet(Alphabet::Chars)
+            .with_unknown_policy(UnknownPolicy::ByteFallback);
+        tokenizer.train(text, 300, false);
+        assert!(tokenizer.char_ids.contains_key(&'ö'));
+
+        assert_eq!(tokenizer.prune_unused_chars("héllo"), 1);
+        assert!(!tokenizer.char_ids.contains_key(&'ö'));
+        assert!(tokenizer.char_ids.contains_key(&'é'));
+        assert!((0..256).all(|id| tokenizer.vocab.contains_key(&id)));
+        assert_eq!(tokenizer.contiguous_ids(), (0..tokenizer.vocab.len() as u32).collect::<Vec<_>>());
+        assert_eq!(tokenizer.decode(&tokenizer.encode(text)).unwrap(), text);
+
+        // Char ids shift down, the unk special after the vocab follows them and still decodes to itself
+        let mut strict = BasicTokenizer::new()
+            .with_alphabet(Alphabet::Chars)
+            .with_unknown_policy(UnknownPolicy::Unk(1000));
+        strict.register_special_tokens(&[("<unk>", 1000)]);
+        strict.train(text, 300, false);
+        let e_acute = strict.char_ids[&'é'];
+        assert_eq!(strict.prune_unused_chars("héllo"), 1);
+        let unk = strict.vocab.len() as u32;
+        assert_eq!(strict.unknown, UnknownPolicy::Unk(unk));
+        assert_eq!(strict.special_tokens["<unk>"], unk);
+        assert!(strict.char_ids[&'é'] <= e_acute);
+        assert_eq!(strict.decode(&strict.encode("hö")).unwrap(), "h<unk>");
+
+        let mut bytes = BasicTokenizer::new();
+        bytes.train(text, 300, false);
+        let vocab_len = bytes.vocab.len();
+        assert_eq!(bytes.prune_unused_chars(""), 0);
+        assert_eq!(bytes.vocab.len(), vocab_len);
+    }
+
+    #[test]
+    fn test_encode_with_stats() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        let s = "Self driving is the future! aaaaaaaa";
+        let (ids, stats) = tokenizer.encode_with_stats(s);
+        assert_eq!(ids, tokenizer.encode(s));
+        assert_eq!(stats.merges_applied, s.len() - ids.len());
+        assert!(stats.iterations > 0 && stats.iterations <= stats.merges_applied);
+
+        assert_eq!(tokenizer.encode_with_stats("").1, EncodeStats::default());
+    }
+
+    #[test]
+    fn test_encode_parallel() {
+        let text = "ünïcödé wörds ラーメン 🙄🙄 and ascii ".repeat(50);
+        let points = split_points(&text, 7, |_| true);
+        assert!(points.iter().all(|&i| text.is_char_boundary(i)));
+        assert_eq!((points[0], *points.last().unwrap()), (0, text.len()));
+        assert_eq!(split_points("", 4, |_| true), [0, 0]);
+
+        let mut tokenizer = BasicTokenizer::new().with_word_marker('▁');
+        tokenizer.train(&text, 400, false);
+        for parts in [1, 2, 7, 1000] {
+            assert_eq!(tokenizer.encode_parallel(&text, parts), tokenizer.encode(&text));
+        }
+
+        let mut plain = BasicTokenizer::new();
+        plain.train(&text, 400, false);
+        assert_eq!(plain.encode_parallel(&text, 7), plain.encode(&text));
+
+        // Spaces are collapsed over the whole text, not slice by slice, which would keep one space per cut
+        let mut collapsing = BasicTokenizer::new().with_word_marker('▁').with_normalizer(collapse_spaces);
+        collapsing.train(&text, 400, false);
+        let spaced = "Self    driving  is   the future  of   cars ".repeat(8);
+        for parts in [2, 7, 1000] {
+            assert_eq!(collapsing.encode_parallel(&spaced, parts), collapsing.encode(&spaced));
+        }
+    }
+
+    #[test]
+    fn test_longest_token_prefix() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        let trie = tokenizer.trie();
+
+        let (&id, token) = tokenizer.vocab.iter().max_by_key(|&(&id, token)| (token.len(), id)).unwrap();
+        let mut input = token.to_vec();
+        input.extend_from_slice(b"\xff\xfe");
+        assert_eq!(trie.longest_token_prefix(&input), Some((id, token.len())));
+
+        // Nothing longer than the single byte starts with 0xff
+        assert_eq!(trie.longest_token_prefix(b"\xff\xfe"), Some((0xff, 1)));
+        assert_eq!(trie.longest_token_prefix(b""), None);
+    }
+
+    #[test]
+    fn test_shadowed_merges() {
+        let (a, b, c) = (b'a' as u32, b'b' as u32, b'c' as u32);
+        // "bc" always goes first, so "ab" never gets the chance to merge on "abc"
+        let tokenizer = BasicTokenizer::replay_merges(vec![((b, c), 256), ((a, b), 257), ((a, 256), 258)]).unwrap();
+        let corpus = "abc abc abc";
+
+        assert_eq!(tokenizer.used_merges(corpus), HashSet::from([(b, c), (a, 256)]));
+        assert_eq!(tokenizer.shadowed_merges(corpus), [(a, b)]);
+        // Without "ab" in the corpus the merge is just unused, not shadowed
+        assert!(tokenizer.shadowed_merges("bc bc").is_empty());
+    }
+
+    #[test]
+    fn test_timed_progress_eta() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut etas = Vec::new();
+        let mut tokenizer = BasicTokenizer::new().with_timed_progress();
+        tokenizer.train_with_progress(&text, 300, |progress| etas.push(progress.eta));
+
+        let etas: Vec<_> = etas.into_iter().map(|eta| eta.expect("Timed training has an ETA")).collect();
+        assert!(etas[0] > etas[etas.len() / 2]);
+        assert_eq!(*etas.last().unwrap(), std::time::Duration::ZERO);
+
+        let mut untimed = BasicTokenizer::new();
+        untimed.train_with_progress(&text, 260, |progress| assert!(progress.eta.is_none()));
+    }
+
+    #[test]
+    fn test_progress_without_total() {
+        // Training to a ratio has no merge count to aim for, so neither a percentage nor an ETA
+        let stats = HashMap::new();
+        let progress = TrainProgress {
+            step: 3, total: None, pair: (97, 98), minted_idx: 258, frequency: 5, token: b"ab", stats: &stats, len: 10, eta: None
+        };
+        assert_eq!(ProgressFormat::STANDARD.format(&progress), "3\nMerged [97] [98] -> [258] (ab)");
+
+        let mut totals = Vec::new();
+        let mut tokenizer = BasicTokenizer::new().with_timed_progress();
+        let pieces = ["aaabdaaabac"];
+        tokenizer.train_pieces(&pieces, None, 2, seq_get_stats, &mut |progress| {
+            totals.push((progress.total, progress.eta));
+            ControlFlow::Continue(())
+        });
+        assert!(!totals.is_empty());
+        assert!(totals.iter().all(|&progress| progress == (None, None)));
+    }
+
+    #[test]
+    fn test_from_merges() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 400, false);
+
+        let merges: Vec<((u32, u32), u32)> = tokenizer.merges.iter().map(|(&pair, &idx)| (pair, idx)).collect();
+        let rebuilt = BasicTokenizer::from_merges(&merges);
+        assert_eq!(rebuilt.vocab, tokenizer.vocab);
+        assert_eq!(rebuilt.merges, tokenizer.merges);
+        assert_eq!(rebuilt.encode("Self driving is the future!"), tokenizer.encode("Self driving is the future!"));
+    }
+
+    #[test]
+    fn test_decode_presized() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        tokenizer.register_special_tokens(&[("<|end|>", 1000)]);
+
+        let mut ids = tokenizer.encode(&text);
+        ids.push(1000);
+        ids.extend(tokenizer.encode("Self driving is the future! 🙄"));
+        let mut expected: Vec<u8> = Vec::new();
+        for id in &ids {
+            match tokenizer.inverse_special_tokens.get(id) {
+                Some(special) => expected.extend_from_slice(special.as_bytes()),
+                None => expected.extend_from_slice(&tokenizer.vocab[id])
+            }
+        }
+        assert_eq!(tokenizer.decode(&ids).unwrap().as_bytes(), expected);
+        assert_eq!(tokenizer.decode_skip_special(&ids).unwrap(), tokenizer.decode(&ids).unwrap().replace("<|end|>", ""));
+        assert!(matches!(tokenizer.decode(&[1, 5000]), Err(DecodeError::UnknownId(5000))));
+    }
+
+    // Normalizer of the tests, runs of spaces become a single one and spaces at the edges are dropped
+    fn collapse_spaces(text: &str) -> Cow<'_, str> {
+        if !text.contains("  ") { return text.into() }
+        text.split(' ').filter(|word| !word.is_empty()).collect::<Vec<_>>().join(" ").into()
+    }
+
+    #[test]
+    fn test_custom_normalizer() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new().with_normalizer(collapse_spaces);
+        tokenizer.train(&text, 300, false);
+
+        let spaced = "Self    driving  is the   future!";
+        assert_eq!(tokenizer.encode(spaced), tokenizer.encode("Self driving is the future!"));
+        assert_eq!(tokenizer.decode(&tokenizer.encode(spaced)).unwrap(), "Self driving is the future!");
+    }
+
+    #[test]
+    fn test_batch_length_stats() {
+        // Without merges every byte is a token, so lengths are known
+        let tokenizer = BasicTokenizer::new();
+        let stats = tokenizer.batch_length_stats(&["a", "abcd", "ab", "abcdefgh"]);
+        assert_eq!(stats, LengthStats { min: 1, max: 8, mean: 3.75, median: 3.0 });
+        assert_eq!(tokenizer.batch_length_stats(&["abc", "", "é"]).median, 2.0);
+        assert_eq!(tokenizer.batch_length_stats(&[]), LengthStats::default());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        let dir = std::env::temp_dir();
+        let vocab_path = dir.join("toktokenizer_fingerprint_vocab.model");
+        let merges_path = dir.join("toktokenizer_fingerprint_merges.txt");
+        tokenizer.save(vocab_path.to_str().unwrap(), merges_path.to_str().unwrap()).unwrap();
+        let loaded = BasicTokenizer::load(merges_path.to_str().unwrap()).unwrap();
+        assert_eq!(loaded.fingerprint(), tokenizer.fingerprint());
+
+        let mut other = BasicTokenizer::new();
+        other.train(&text, 301, false);
+        assert_ne!(other.fingerprint(), tokenizer.fingerprint());
+        assert_ne!(BasicTokenizer::new().with_word_marker('▁').fingerprint(), BasicTokenizer::new().fingerprint());
+    }
+
+    #[test]
+    fn test_decode_segments() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        tokenizer.register_special_tokens(&[("<|turn|>", 1000)]);
+
+        let mut ids = vec![1000];
+        ids.extend(tokenizer.encode("Hello there"));
+        ids.push(1000);
+        ids.extend(tokenizer.encode("General Kenobi! 🙄"));
+        ids.extend([1000, 1000]);
+        assert_eq!(tokenizer.decode_segments(&ids, 1000).unwrap(), ["", "Hello there", "General Kenobi! 🙄", "", ""]);
+        assert_eq!(tokenizer.decode_segments(&[], 1000).unwrap(), [""]);
+        assert!(matches!(tokenizer.decode_segments(&[1000, 5000], 1000), Err(DecodeError::UnknownId(5000))));
+    }
+
+    #[test]
+    fn test_encode_pooled() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        let s = "Self driving is the future! 🙄";
+        let pooled = tokenizer.encode_pooled(s);
+        assert_eq!(*pooled, tokenizer.encode(s)[..]);
+        let buffer = pooled.as_ptr();
+        drop(pooled);
+
+        // The next encode on this thread gets the same allocation back
+        let pooled = tokenizer.encode_pooled("Self driving");
+        assert_eq!(pooled.as_ptr(), buffer);
+        assert_eq!(*pooled, tokenizer.encode("Self driving")[..]);
+    }
+
+    #[test]
+    fn test_merge_chunks_compacts() {
+        let mut chunks = vec![vec![97; 1000], vec![97, 98, 99]];
+        merge_chunks(&mut chunks, (97, 97), 256);
+        assert_eq!(chunks[0], [256; 500]);
+        assert!(chunks[0].capacity() < 1000);
+        assert!(chunks.iter().all(|chunk| chunk.capacity() < chunk.len() * 2));
+    }
+
+    #[test]
+    fn test_merges_from() {
+        let (a, b, c, d) = (b'a' as u32, b'b' as u32, b'c' as u32, b'd' as u32);
+        // ab=256, cd=257, abc=258, abcd=259, abab=260
+        let tokenizer = BasicTokenizer::replay_merges(vec![
+            ((a, b), 256), ((c, d), 257), ((256, c), 258), ((256, 257), 259), ((256, 256), 260)
+        ]).unwrap();
+
+        assert_eq!(tokenizer.merges_from((a, b)), [(256, c), (256, 257), (256, 256)]);
+        assert_eq!(tokenizer.merges_from((c, d)), [(256, 257)]);
+        assert!(tokenizer.merges_from((256, c)).is_empty());
+        assert!(tokenizer.merges_from((d, d)).is_empty());
+
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut trained = BasicTokenizer::new();
+        trained.train(&text, 300, false);
+        let base = *trained.merges.iter().find(|&(_, &idx)| idx == 256).unwrap().0;
+        for pair in trained.merges_from(base) {
+            let token = &trained.vocab[&trained.merges[&pair]];
+            assert!(token.windows(trained.vocab[&256].len()).any(|w| w == trained.vocab[&256].as_ref()));
+        }
+    }
+
+    #[test]
+    fn test_save_to() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        let (mut vocab, mut merges) = (Vec::new(), Vec::new());
+        tokenizer.save_to(&mut vocab, &mut merges).unwrap();
+
+        let mut expected_vocab = String::new();
+        for idx in 0..300 {
+            expected_vocab.push_str(&format!("[{}] -> ({})\n", idx, String::from_utf8_lossy(&tokenizer.vocab[&idx])));
+        }
+        let mut ranked: Vec<_> = tokenizer.merges.iter().collect();
+        ranked.sort_unstable_by_key(|&(_, idx)| idx);
+        let mut expected_merges = String::new();
+        for (&(idx1, idx2), &idx_minted) in ranked {
+            expected_merges.push_str(&format!("[{idx1}][{idx2}] -> [{idx_minted}]\n"));
+        }
+        assert_eq!(String::from_utf8_lossy(&vocab), expected_vocab);
+        assert_eq!(String::from_utf8(merges).unwrap(), expected_merges);
+    }
+
+    #[test]
+    fn test_min_vocab_for_ratio() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let text = &text[..text.char_indices().nth(5000).map_or(text.len(), |(i, _)| i)];
+
+        let vocab_size = BasicTokenizer::min_vocab_for_ratio(text, 0.6);
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(text, vocab_size, false);
+        assert!(tokenizer.encode(text).len() as f64 / text.len() as f64 <= 0.6);
+
+        let mut smaller = BasicTokenizer::new();
+        smaller.train(text, vocab_size - 1, false);
+        assert!(smaller.encode(text).len() as f64 / text.len() as f64 > 0.6);
+        assert_eq!(BasicTokenizer::min_vocab_for_ratio(text, 1.0), 256);
+    }
+
+    #[test]
+    fn test_repeated_token_runs() {
+        let (a, b, c) = (b'a' as u32, b'b' as u32, b'c' as u32);
+        let tokenizer = BasicTokenizer::replay_merges(vec![((a, b), 256)]).unwrap();
+
+        assert_eq!(tokenizer.repeated_token_runs("ababab c cc abcccc"), [(256, 3), (c, 2), (c, 4)]);
+        assert!(tokenizer.repeated_token_runs("abc").is_empty());
+        assert!(tokenizer.repeated_token_runs("").is_empty());
+        assert_eq!(tokenizer.repeated_token_runs("    x"), [(b' ' as u32, 4)]);
+    }
+
+    #[test]
+    fn test_vocab_size() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizers: Vec<Box<dyn Tokenize>> = vec![
+            Box::new(BasicTokenizer::new()),
+            Box::new(RegexTokenizer::new(GPT4_SPLIT_PATTERN).unwrap())
+        ];
+        for tokenizer in tokenizers.iter_mut() {
+            assert_eq!(tokenizer.vocab_size(), 0);
+            tokenizer.train(&text, 300, false);
+            assert_eq!(tokenizer.vocab_size(), 300);
+        }
+    }
+
+    #[test]
+    fn test_train_from_base() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut base = BasicTokenizer::new();
+        base.train(&text, 280, false);
+
+        let extended = BasicTokenizer::train_from_base(&base, "zqzqzq zqzqzq xwxwxw xwxwxw", 5, false);
+        assert_eq!(base.vocab.len(), 280);
+        assert_eq!(extended.vocab.len(), 285);
+        assert!(base.merges.iter().all(|(pair, idx)| extended.merges.get(pair) == Some(idx)));
+        assert!(extended.merges.iter()
+            .filter(|&(pair, _)| !base.merges.contains_key(pair))
+            .all(|(_, &idx)| (280..285).contains(&idx)));
+        assert_eq!(extended.encode("zqzq").len(), 1);
+    }
+
+    #[test]
+    fn test_encoding_diff() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 400, false);
+
+        let (a, b) = ("Self driving is the future of the world", "Self driving is the fxture of the world");
+        let diff = tokenizer.encoding_diff(a, b);
+        let changed: Vec<usize> = (0..diff.len()).filter(|&i| !matches!(diff[i], DiffOp::Equal(_))).collect();
+        assert!(!changed.is_empty());
+        // The change is one contiguous block, surrounded by untouched tokens
+        assert_eq!(changed.last().unwrap() - changed[0] + 1, changed.len());
+        assert!(matches!(diff[0], DiffOp::Equal(_)) && matches!(diff.last(), Some(DiffOp::Equal(_))));
+
+        // Replaying the diff gives back both encodings
+        let old: Vec<u32> = diff.iter().filter_map(|op| match *op {
+            DiffOp::Equal(id) | DiffOp::Delete(id) => Some(id),
+            DiffOp::Insert(_) => None
+        }).collect();
+        let new: Vec<u32> = diff.iter().filter_map(|op| match *op {
+            DiffOp::Equal(id) | DiffOp::Insert(id) => Some(id),
+            DiffOp::Delete(_) => None
+        }).collect();
+        assert_eq!(old, tokenizer.encode(a));
+        assert_eq!(new, tokenizer.encode(b));
+        assert!(tokenizer.encoding_diff(a, a).iter().all(|op| matches!(op, DiffOp::Equal(_))));
+    }
+
+    #[test]
+    fn test_save_special_tokens_sorted() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let saved = |special: &[(&str, u32)]| {
+            let mut tokenizer = BasicTokenizer::new();
+            tokenizer.train(&text, 300, false);
+            tokenizer.register_special_tokens(special);
+            let (mut vocab, mut merges) = (Vec::new(), Vec::new());
+            tokenizer.save_to(&mut vocab, &mut merges).unwrap();
+            (vocab, merges)
+        };
+
+        // Registration order and hash map seeds must not show in the files
+        let first = saved(&[("<|eos|>", 1002), ("<|bos|>", 1000), ("<|pad|>", 1001)]);
+        let second = saved(&[("<|pad|>", 1001), ("<|bos|>", 1000), ("<|eos|>", 1002)]);
+        assert_eq!(first, second);
+        assert!(String::from_utf8_lossy(&first.0).ends_with("[1000] -> (<|bos|>)\n[1001] -> (<|pad|>)\n[1002] -> (<|eos|>)\n"));
+    }
+
+    #[test]
+    fn test_encode_with_boundaries() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = RegexTokenizer::new(GPT4_SPLIT_PATTERN).unwrap().with_boundary_sentinel(9999);
+        tokenizer.train(&text, 300, false);
+
+        let s = "Hello world, it's 2024!!  ok";
+        let ids = tokenizer.encode_with_boundaries(s);
+        let chunks = tokenizer.split(s);
+        let segments: Vec<&[u32]> = ids.split(|&id| id == 9999).collect();
+        assert_eq!(segments.len(), chunks.len());
+        for (segment, chunk) in segments.iter().zip(&chunks) {
+            assert_eq!(*segment, tokenizer.inner.encode(chunk));
+        }
+        assert_eq!(ids.iter().filter(|&&id| id != 9999).copied().collect::<Vec<_>>(), tokenizer.encode(s));
+    }
+
+    #[test]
+    fn test_max_chunk_len() {
+        let huge = " ".repeat(10_000) + &"é".repeat(3000);
+        let tokenizer = RegexTokenizer::new(GPT4_SPLIT_PATTERN).unwrap();
+        let chunks = tokenizer.split(&huge);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= DEFAULT_MAX_CHUNK_LEN));
+        assert_eq!(chunks.concat(), huge);
+
+        let mut tokenizer = RegexTokenizer::new(GPT4_SPLIT_PATTERN).unwrap().with_max_chunk_len(3);
+        assert!(tokenizer.split("aaaaaaa é🙄").iter().all(|chunk| chunk.len() <= 3 || chunk.chars().count() == 1));
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        tokenizer.train(&text, 300, false);
+        assert_eq!(tokenizer.decode(&tokenizer.encode(&huge)).unwrap(), huge);
+        assert!(tokenizer.inner.vocab.values().all(|token| token.len() <= 3));
+    }
+
+    #[test]
+    fn test_incremental_stats() {
+        let mut chunks = vec![vec![1, 1, 1, 1, 2, 1, 1], vec![3, 1, 1, 1], vec![4, 5]];
+        let mut stats = seq_get_stats(&chunks);
+        apply_delta(&mut stats, merge_chunks(&mut chunks, (1, 1), 9));
+        assert_eq!(chunks, [vec![9, 9, 2, 9], vec![3, 9, 1], vec![4, 5]]);
+        assert_eq!(stats, seq_get_stats(&chunks));
+
+        // Debug builds recount every few merges and panic on any drift
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new().with_word_marker('▁');
+        tokenizer.train(&text, 256 + 20 * STATS_CHECK_INTERVAL, false);
+        let mut regex = RegexTokenizer::new(GPT4_SPLIT_PATTERN).unwrap();
+        regex.train(&(text.clone() + &"a".repeat(100)), 256 + 20 * STATS_CHECK_INTERVAL, false);
+        assert_eq!(tokenizer.vocab.len(), 256 + 20 * STATS_CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn test_vocab_coverage() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        let own = tokenizer.vocab_coverage(&text);
+        let unrelated = tokenizer.vocab_coverage("0123456789 ++-- 9876543210");
+        assert!(own > unrelated, "{own} <= {unrelated}");
+        assert!(own > 0.0 && own <= 1.0);
+        assert_eq!(tokenizer.vocab_coverage(""), 0.0);
+    }
+
+    #[test]
+    fn test_stream_decoder_trailing_bytes() {
+        let tokenizer = BasicTokenizer::replay_merges(Vec::new()).unwrap();
+        // "a🙄" cut after the first two bytes of the emoji
+        let ids: Vec<u32> = "a🙄".bytes().take(3).map(|b| b as u32).collect();
+        let decode = |trailing| {
+            let mut decoder = tokenizer.stream_decoder(trailing);
+            let text: String = ids.iter().map(|&id| decoder.push(id).unwrap()).collect();
+            decoder.finish().map(|(tail, bytes)| (text + &tail, bytes))
+        };
+
+        assert!(matches!(decode(TrailingBytes::Error), Err(DecodeError::InvalidUtf8(_))));
+        assert_eq!(decode(TrailingBytes::Replace).unwrap(), ("a\u{FFFD}".to_string(), Vec::new()));
+        assert_eq!(decode(TrailingBytes::Return).unwrap(), ("a".to_string(), "🙄".as_bytes()[..2].to_vec()));
+
+        let mut decoder = tokenizer.stream_decoder(TrailingBytes::Error);
+        let text: String = "a🙄b".bytes().map(|b| decoder.push(b as u32).unwrap()).collect();
+        assert_eq!(text, "a🙄b");
+        assert_eq!(decoder.finish().unwrap(), (String::new(), Vec::new()));
+    }
+
+    #[test]
+    fn test_encode_span() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new().with_bos(1000).with_eos(1001);
+        tokenizer.train(&text, 400, false);
+
+        let full = "Self driving is the future! 🙄 and the present";
+        let offsets = tokenizer.encode_with_offsets(full).unwrap();
+        assert_eq!(offsets.iter().map(|(id, _)| *id).collect::<Vec<_>>(), tokenizer.encode(full));
+        assert_eq!((offsets[0].1.clone(), offsets.last().unwrap().1.clone()), (0..0, full.len()..full.len()));
+        for (id, range) in &offsets[1..offsets.len() - 1] {
+            assert_eq!(&full.as_bytes()[range.clone()], tokenizer.vocab[id].as_ref());
+        }
+
+        // A span made of whole tokens gives back exactly that slice of the full encoding
+        let (first, last) = (3, offsets.len() - 4);
+        let span = offsets[first].1.start..offsets[last].1.end;
+        let expected: Vec<u32> = offsets[first..=last].iter().map(|(id, _)| *id).collect();
+        assert_eq!(tokenizer.encode_span(full, span), expected);
+        assert!(tokenizer.encode_span(full, 0..0).is_empty());
+
+        // Unknown chars replaced by the unk id still cover their whole char, or fail with the strict policy
+        let mut chars = BasicTokenizer::new().with_alphabet(Alphabet::Chars).with_unknown_policy(UnknownPolicy::Unk(0));
+        chars.train("abc abc", 256, false);
+        assert_eq!(chars.encode_with_offsets("aé🙄b").unwrap(), [(97, 0..1), (0, 1..3), (0, 3..7), (98, 7..8)]);
+        let chars = chars.with_unknown_policy(UnknownPolicy::Error);
+        assert_eq!(chars.encode_with_offsets("aé"), Err(EncodeError::UnknownChar('é')));
+
+        // Each marker byte but the first is an empty range right after its space
+        let mut marked = BasicTokenizer::new().with_word_marker('▁');
+        marked.train("a b", 256, false);
+        assert_eq!(marked.encode_with_offsets("a b").unwrap(), [(97, 0..1), (0xe2, 1..2), (0x96, 2..2), (0x81, 2..2), (98, 2..3)]);
+    }
+
+    #[test]
+    fn test_train_for() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        // An exhausted budget still lets the first merge finish, then stops
+        assert_eq!(tokenizer.train_for(&text, 100_000, std::time::Duration::ZERO, false), 1);
+        assert_eq!(tokenizer.vocab.len(), 257);
+        assert_eq!(tokenizer.merges.len(), 1);
+        assert_eq!(tokenizer.decode(&tokenizer.encode(&text)).unwrap(), text);
+
+        let mut capped = BasicTokenizer::new();
+        assert_eq!(capped.train_for(&text, 270, std::time::Duration::from_secs(3600), false), 14);
+    }
+
+    #[test]
+    fn test_base_byte_range() {
+        let text = "héllo wörld, héllo wörld";
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(text, 270, false);
+        assert_eq!(tokenizer.base_byte_range(), 0..256);
+        assert!(tokenizer.is_base_byte(0) && tokenizer.is_base_byte(255));
+        assert!(tokenizer.merges.values().all(|&idx| !tokenizer.is_base_byte(idx)));
+
+        let mut chars = BasicTokenizer::new().with_alphabet(Alphabet::Chars);
+        chars.train(text, 270, false);
+        assert_eq!(chars.base_byte_range(), 0..258);
+        assert!(chars.char_ids.values().all(|&id| chars.is_base_byte(id)));
+        assert!(chars.merges.values().all(|&idx| !chars.is_base_byte(idx)));
+        for &minted in chars.merges.values() {
+            assert_eq!(chars.base_bytes_of(minted), chars.vocab[&minted].as_ref());
+        }
+    }
+
+    #[test]
+    fn test_reverse_index() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        assert!(tokenizer.vocab.iter().all(|(&id, bytes)| tokenizer.token_to_id(bytes) == Some(id)));
+        assert_eq!(tokenizer.token_to_id(b"\xff\xfe"), None);
+
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let (vocab, merges, reverse) = (path("toktokenizer_rev_vocab.model"), path("toktokenizer_rev_merges.txt"), path("toktokenizer_rev_index.txt"));
+        tokenizer.save(&vocab, &merges).unwrap();
+        tokenizer.save_reverse_index(&reverse).unwrap();
+
+        let loaded = BasicTokenizer::load_with_reverse_index(&merges, &reverse).unwrap();
+        assert!(loaded.reverse_vocab.get().is_some());
+        assert!(tokenizer.vocab.iter().all(|(&id, bytes)| loaded.token_to_id(bytes) == Some(id)));
+
+        // An entry pointing at the wrong id is rejected
+        let content = std::fs::read_to_string(&reverse).unwrap().replace(" 256\n", " 257\n");
+        std::fs::write(&reverse, content).unwrap();
+        let err = BasicTokenizer::load_with_reverse_index(&merges, &reverse).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_progress_format_presets() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut lines = Vec::new();
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train_with_progress(&text, 260, |progress| {
+            lines.push((progress.frequency, ProgressFormat::DETAILED.format(progress), ProgressFormat::IDS_ONLY.format(progress),
+                ProgressFormat::default().format(progress)));
+        });
+
+        let (frequency, detailed, ids_only, standard) = &lines[0];
+        assert!(detailed.ends_with(&format!(" x{frequency}")));
+        assert!(detailed.starts_with(standard.as_str()));
+        assert_eq!(*ids_only, format!("1/4\nMerged [{}] [{}] -> [256]", tokenizer.vocab[&256][0], tokenizer.vocab[&256][1]));
+        assert_eq!(ProgressFormat::default(), ProgressFormat::STANDARD);
+    }
+
+    #[test]
+    fn test_encode_u8() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 256, false);
+
+        let s = "Self driving is the future! 🙄";
+        let ids = tokenizer.encode_u8(s).unwrap();
+        assert_eq!(ids.len(), s.len());
+        assert_eq!(tokenizer.decode_u8(&ids).unwrap(), s);
+
+        let mut larger = BasicTokenizer::new();
+        larger.train(&text, 300, false);
+        let err = larger.encode_u8(s).unwrap_err();
+        assert!(matches!(err, EncodeError::IdTooLarge { id, bits: 8 } if id >= 256));
+        assert!(err.to_string().ends_with("does not fit in 8 bits"));
+    }
+
+    #[test]
+    fn test_validate_merge_order() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        assert_eq!(tokenizer.validate(), Vec::new());
+
+        // A merge using a later id, and one using its own id
+        tokenizer.merges.insert((299, 97), 256);
+        tokenizer.merges.insert((305, 97), 305);
+        assert_eq!(tokenizer.validate(), vec![
+            ModelIssue::NonMonotonicMerge { pair: (299, 97), minted: 256 },
+            ModelIssue::NonMonotonicMerge { pair: (305, 97), minted: 305 },
+        ]);
+    }
+
+    #[test]
+    fn test_chain_tokenizer() {
+        let mut numbers = BasicTokenizer::new();
+        numbers.train(&"123".repeat(50), 260, false);
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut general = BasicTokenizer::new();
+        general.train(&text, 300, false);
+        let number_ids = numbers.encode("123");
+        let word_ids = general.encode(" driving");
+
+        let chain = ChainTokenizer::new(GPT4_SPLIT_PATTERN, vec![Box::new(numbers), Box::new(general)]).unwrap();
+        assert_eq!(chain.vocab_size(), 560);
+        let input = "Self driving 123 🙄";
+        let ids = chain.encode(input);
+        assert_eq!(chain.decode(&ids).unwrap(), input);
+
+        // Numbers go to the first tokenizer as is, words fall back to the shifted ids of the second
+        let shifted: Vec<u32> = word_ids.iter().map(|id| id + 260).collect();
+        assert!(ids.windows(number_ids.len()).any(|w| w == number_ids.as_slice()));
+        assert!(ids.windows(shifted.len()).any(|w| w == shifted.as_slice()));
+        assert_eq!(chain.decode(&[560]), Err(DecodeError::UnknownId(560)));
+
+        assert!(matches!(ChainTokenizer::new(GPT4_SPLIT_PATTERN, Vec::new()), Err(ChainError::Empty)));
+        let empty_match = ChainTokenizer::new(r"\w*", vec![Box::new(BasicTokenizer::new())]);
+        assert!(matches!(empty_match, Err(ChainError::Pattern(PatternError::MatchesEmpty))));
+    }
+
+    #[test]
+    fn test_char_token_map() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        let input = "Self 🙄 driving é!";
+        let offsets = tokenizer.encode_with_offsets(input).unwrap();
+        let map = tokenizer.char_token_map(input);
+        assert_eq!(map.len(), input.chars().count());
+        for ((at, _), &token) in input.char_indices().zip(&map) {
+            assert!(offsets[token].1.contains(&at));
+        }
+
+        // Without merges the emoji is 4 byte tokens, the char after it lands 4 tokens later
+        let mut bytes_only = BasicTokenizer::new().with_bos(1000);
+        bytes_only.train(&text, 256, false);
+        assert_eq!(bytes_only.char_token_map("a🙄b"), vec![1, 2, 6]);
+
+        // Chars are those of the normalized text, one entry less for the dropped \r
+        let newlines = bytes_only.with_normalized_newlines();
+        assert_eq!(newlines.char_token_map("a\r\nb"), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_special_matching_ignore_case() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new().with_special_matching(SpecialMatching::IgnoreAsciiCase);
+        tokenizer.train(&text, 300, false);
+        tokenizer.register_special_tokens(&[("<|endoftext|>", 1000), ("<|pad|>", 1001)]);
+
+        let mut expected = tokenizer.encode("Self driving");
+        expected.push(1000);
+        expected.extend(tokenizer.encode(" is the future!"));
+        let ids = tokenizer.encode("Self driving<|ENDOFTEXT|> is the future!");
+        assert_eq!(ids, expected);
+        assert_eq!(tokenizer.decode(&ids).unwrap(), "Self driving<|endoftext|> is the future!");
+
+        let exact = tokenizer.clone().with_special_matching(SpecialMatching::Exact);
+        assert!(!exact.encode("<|ENDOFTEXT|>").contains(&1000));
+        assert_eq!(exact.encode("<|endoftext|>"), vec![1000]);
+        assert!(!tokenizer.with_special_matching(SpecialMatching::Off).encode("<|pad|>").contains(&1001));
+    }
+
+    #[test]
+    fn test_special_matching_encode_paths() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new().with_word_marker('▁').with_special_matching(SpecialMatching::Exact);
+        tokenizer.train(&text, 400, false);
+        tokenizer.register_special_tokens(&[("<|endoftext|>", 1000)]);
+        let input = "Self driving<|endoftext|> is the future!<|endoftext|>".repeat(3);
+        let expected = tokenizer.encode(&input);
+        assert_eq!(expected.iter().filter(|&&id| id == 1000).count(), 6);
+
+        // Every encode method finds the specials the same way
+        assert_eq!(tokenizer.encode_flat(&input), expected);
+        assert_eq!(*tokenizer.encode_pooled(&input), *expected);
+        assert_eq!(tokenizer.encode_with_stats(&input).0, expected);
+        assert_eq!(tokenizer.encode_with_dropout(&input, 0.0, 7), expected);
+        for parts in [1, 3, 1000] {
+            assert_eq!(tokenizer.encode_parallel(&input, parts), expected);
+        }
+        let offsets = tokenizer.encode_with_offsets(&input).unwrap();
+        assert_eq!(offsets.iter().map(|(id, _)| *id).collect::<Vec<_>>(), expected);
+        let (_, range) = offsets.iter().find(|(id, _)| *id == 1000).unwrap();
+        assert_eq!(&input[range.clone()], "<|endoftext|>");
+        assert_eq!(tokenizer.encode_optimal(&input).iter().filter(|&&id| id == 1000).count(), 6);
+        assert!(tokenizer.assert_single_token("<|endoftext|>"));
+        assert!(tokenizer.used_merges("<|endoftext|>").is_empty());
+
+        // The regex chunks are cut around the specials, not through them
+        let mut regex = RegexTokenizer::new(GPT4_SPLIT_PATTERN).unwrap().with_special_matching(SpecialMatching::Exact);
+        regex.train(&text, 300, false);
+        regex.register_special_tokens(&[("<|endoftext|>", 1000)]);
+        let ids = regex.encode(&input);
+        assert_eq!(ids.iter().filter(|&&id| id == 1000).count(), 6);
+        assert_eq!(regex.decode(&ids).unwrap(), input);
+        assert_eq!(regex.encode_with_boundaries("a<|endoftext|>"), [97, u32::MAX, 1000]);
+    }
+
+    #[test]
+    fn test_sentencepiece_export() {
+        // Reads one protobuf field, as (field number, varint value or length delimited bytes)
+        fn field<'a>(bytes: &mut &'a [u8]) -> (u64, u64, &'a [u8]) {
+            let mut varint = || {
+                let mut value = 0;
+                for shift in (0..).step_by(7) {
+                    let byte = bytes[0];
+                    *bytes = &bytes[1..];
+                    value |= ((byte & 0x7f) as u64) << shift;
+                    if byte < 0x80 { break }
+                }
+                value
+            };
+            let key = varint();
+            match key & 7 {
+                0 => (key >> 3, varint(), &[]),
+                2 => {
+                    let len = varint() as usize;
+                    let (data, rest) = bytes.split_at(len);
+                    *bytes = rest;
+                    (key >> 3, 0, data)
+                }
+                5 => {
+                    let (data, rest) = bytes.split_at(4);
+                    *bytes = rest;
+                    (key >> 3, 0, data)
+                }
+                wire => panic!("unexpected wire type {wire}"),
+            }
+        }
+
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        tokenizer.register_special_tokens(&[("<|end|>", 1000)]);
+        let exported = tokenizer.to_sentencepiece();
+
+        let mut pieces = Vec::new();
+        let mut trainer = HashMap::new();
+        let mut normalizer = HashMap::new();
+        let mut rest = exported.as_slice();
+        while !rest.is_empty() {
+            match field(&mut rest) {
+                (1, _, mut message) => {
+                    let (_, _, piece) = field(&mut message);
+                    let (_, _, score) = field(&mut message);
+                    let (_, kind, _) = field(&mut message);
+                    pieces.push((String::from_utf8(piece.to_vec()).unwrap(), f32::from_le_bytes(score.try_into().unwrap()), kind));
+                }
+                (2, _, mut spec) => while !spec.is_empty() {
+                    let (number, value, _) = field(&mut spec);
+                    trainer.insert(number, value);
+                }
+                (3, _, mut spec) => while !spec.is_empty() {
+                    let (number, value, bytes) = field(&mut spec);
+                    normalizer.insert(number, (value, bytes));
+                }
+                (number, _, _) => panic!("unexpected field {number}"),
+            }
+        }
+
+        // Piece ids are the ids of the model, unused ids up to the special are placeholders, unk comes last
+        assert_eq!(pieces.len(), 1002);
+        assert_eq!(pieces[0x41], ("<0x41>".to_string(), 0.0, 6));
+        for idx in 256..300 {
+            let token = String::from_utf8(tokenizer.vocab[&idx].to_vec()).unwrap().replace(' ', "▁");
+            assert_eq!(pieces[idx as usize], (token, -(idx as f32), 1));
+        }
+        assert_eq!(pieces[300], ("<unused300>".to_string(), 0.0, 5));
+        assert_eq!(pieces[1000], ("<|end|>".to_string(), 0.0, 4));
+        assert_eq!(pieces[1001], ("<unk>".to_string(), 0.0, 2));
+        assert_eq!(trainer[&3], 2);
+        assert_eq!(trainer[&4], pieces.len() as u64);
+        assert_eq!(trainer[&35], 1);
+        assert_eq!(trainer[&40], 1001);
+
+        // Text is only escaped, never rewritten, matching what encode sees
+        assert_eq!(normalizer[&1].1, b"identity");
+        assert_eq!((normalizer[&3].0, normalizer[&4].0, normalizer[&5].0), (0, 0, 1));
+    }
+
+    #[test]
+    #[ignore = "needs python3 with the sentencepiece package"]
+    fn test_sentencepiece_loads() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+        let path = std::env::temp_dir().join("toktokenizer_export.model");
+        let path = path.to_str().unwrap();
+        tokenizer.save_sentencepiece(path).unwrap();
+
+        // SentencePiece loads the model and decodes our ids, piece for id
+        let s = "Self driving is the future!";
+        let ids: Vec<String> = tokenizer.encode(s).iter().map(|id| id.to_string()).collect();
+        let script = "import sys, sentencepiece as spm\n\
+            sp = spm.SentencePieceProcessor(model_file=sys.argv[1])\n\
+            print(sp.get_piece_size())\n\
+            print(sp.decode([int(id) for id in sys.argv[2].split()]))";
+        let output = std::process::Command::new("python3").args(["-c", script, path, &ids.join(" ")]).output().unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(stdout.lines().collect::<Vec<_>>(), [(tokenizer.vocab.len() + 1).to_string().as_str(), s]);
+    }
+
+    #[test]
+    fn test_id_of_token_str() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 300, false);
+
+        let merged = String::from_utf8(tokenizer.vocab[&299].to_vec()).unwrap();
+        assert_eq!(tokenizer.id_of_token_str(&merged), Some(299));
+        assert_eq!(tokenizer.id_of_token_str("a"), Some(97));
+        assert_eq!(tokenizer.id_of_token_str("Self driving is the future!"), None);
+        assert!(tokenizer.encode("Self driving is the future!").len() > 1);
+    }
+
+    #[test]
+    fn test_empty_matches() {
+        assert!(matches!(RegexTokenizer::new(r"\w*"), Err(PatternError::MatchesEmpty)));
+        assert!(matches!(RegexTokenizer::new(r"(\w"), Err(PatternError::Regex(_))));
+
+        // Matches empty between words, and before every char that is not a letter. The chars the search
+        // steps over after an empty match are chunks of their own, none of the input is dropped
+        let text = "Self driving  is 🙄!";
+        let corpus = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = RegexTokenizer::allowing_empty_matches(r"\w*|(?=\s)").unwrap();
+        assert_eq!(tokenizer.split(text), vec!["Self", " ", "driving", " ", " ", "is", " ", "🙄", "!"]);
+        tokenizer.train(&corpus, 300, false);
+        assert_eq!(tokenizer.decode(&tokenizer.encode(text)).unwrap(), text);
+
+        // Only matches empty in context, so `new` accepts it
+        let mut boundaries = RegexTokenizer::new(r"\b|\w+").unwrap();
+        boundaries.train(&corpus, 300, false);
+        assert_eq!(boundaries.decode(&boundaries.encode("ab cd")).unwrap(), "ab cd");
+        assert_eq!(boundaries.decode(&boundaries.encode(text)).unwrap(), text);
+        let mut chain = ChainTokenizer::new(r"\b|\w+", vec![Box::new(BasicTokenizer::new())]).unwrap();
+        chain.train(&corpus, 300, false);
+        assert_eq!(chain.decode(&chain.encode(text)).unwrap(), text);
+
+        let mut trained = RegexTokenizer::allowing_empty_matches(r"\s|\w*").unwrap();
+        trained.train(&corpus, 300, false);
+        assert_eq!(trained.decode(&trained.encode(text)).unwrap(), text);
+    }
+
+    #[test]
+    fn test_export_used_subset() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 400, false);
+        let corpus = "Self driving is the future! Self driving cars drive themselves.";
+
+        let subset = tokenizer.export_used_subset(corpus);
+        assert!(subset.vocab_size() < tokenizer.vocab_size());
+        assert_eq!(subset.contiguous_ids(), (0..subset.vocab_size() as u32).collect::<Vec<_>>());
+        let tokens = |tknzr: &BasicTokenizer| -> Vec<Bytes> {
+            tknzr.encode(corpus).iter().map(|id| tknzr.vocab[id].clone()).collect()
+        };
+        assert_eq!(tokens(&subset), tokens(&tokenizer));
+        assert_eq!(subset.decode(&subset.encode(corpus)).unwrap(), corpus);
+    }
+
+    #[test]
+    fn test_grapheme_splitting_tokens() {
+        // Accents written as a combining mark after the letter
+        let corpus = "cafe\u{301} ole\u{301} re\u{301}sume\u{301} nai\u{308}ve ".repeat(20);
+        let mut unchecked = BasicTokenizer::new();
+        unchecked.train(&corpus, 300, false);
+        assert_eq!(unchecked.grapheme_warnings(), None);
+        let mut tokenizer = BasicTokenizer::new().with_grapheme_warnings();
+        tokenizer.train(&corpus, 300, false);
+
+        let flagged = tokenizer.grapheme_warnings().unwrap().to_vec();
+        assert_eq!(flagged, tokenizer.grapheme_splitting_tokens(&corpus).unwrap());
+        assert!(flagged.iter().all(|id| tokenizer.merges.values().any(|idx| idx == id)));
+        // The mark of ï ends up starting a token, apart from its letter
+        assert!(tokenizer.id_of_token_str("\u{308}ve ").is_some_and(|id| flagged.contains(&id)));
+        assert!(tokenizer.id_of_token_str("cafe\u{301}").is_none_or(|id| !flagged.contains(&id)));
+
+        // Regional indicators pair up into flags: 🇷 and 🇩 are whole chars (258, 259) but each is half a flag,
+        // [🇷🇩] takes the second half of 🇫🇷 and the first of 🇩🇪, the raw bytes before them cut a char
+        let flags = BasicTokenizer::from_merges(&[
+            ((0xF0, 0x9F), 256), ((256, 0x87), 257), ((257, 0xB7), 258), ((257, 0xA9), 259), ((258, 259), 260)
+        ]);
+        assert_eq!(flags.grapheme_splitting_tokens("🇫🇷🇩🇪").unwrap(), [256, 257, 258, 259, 260]);
+        assert_eq!(flags.grapheme_splitting_tokens("🇷").unwrap(), [256, 257]);
+
+        // The vowel and final of a Hangul syllable written as jamo are whole chars (257, 259), and so is
+        // their pair (260), but the syllable starts with the consonant. Without it [ᅡᆨ] is the whole cluster
+        let jamo = BasicTokenizer::from_merges(&[
+            ((0xE1, 0x85), 256), ((256, 0xA1), 257), ((0xE1, 0x86), 258), ((258, 0xA8), 259), ((257, 259), 260)
+        ]);
+        assert_eq!(jamo.grapheme_splitting_tokens("\u{1100}\u{1161}\u{11A8}").unwrap(), [256, 257, 258, 259, 260]);
+        assert_eq!(jamo.grapheme_splitting_tokens("\u{1161}\u{11A8}").unwrap(), [256, 257, 258, 259]);
+
+        // A spacing mark belongs to its consonant: ि alone cuts कि, like the first two bytes of क
+        let spacing = BasicTokenizer::from_merges(&[((0xE0, 0xA4), 256), ((256, 0xBF), 257)]);
+        assert_eq!(spacing.grapheme_splitting_tokens("कि").unwrap(), [256, 257]);
+
+        // A virama joins two consonants into one cluster, so ष alone cuts क्ष
+        let conjunct = BasicTokenizer::from_merges(&[((0xE0, 0xA4), 256), ((256, 0xB7), 257)]);
+        assert_eq!(conjunct.grapheme_splitting_tokens("क्ष").unwrap(), [256, 257]);
+        assert_eq!(conjunct.grapheme_splitting_tokens("ष").unwrap(), [256]);
+
+        // Continued training checks its own corpus
+        let mut continued = BasicTokenizer::new().with_grapheme_warnings();
+        continued.train("abc abc", 258, false);
+        assert_eq!(continued.grapheme_warnings(), Some(&[][..]));
+        continued.continue_training(&corpus, 20, false);
+        assert_eq!(continued.grapheme_warnings().unwrap(), continued.grapheme_splitting_tokens(&corpus).unwrap());
+    }
+
+    #[test]
+    fn test_plan_merges() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let plan = BasicTokenizer::plan_merges(&text, 400);
+        assert_eq!(plan.len(), 400 - 256);
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 400, false);
+        let mut ranked: Vec<((u32, u32), u32)> = tokenizer.merges.iter().map(|(&pair, &idx)| (pair, idx)).collect();
+        ranked.sort_unstable_by_key(|&(_, idx)| idx);
+        assert_eq!(plan, ranked);
+
+        // Runs out of pairs: "abab" only has "ab", then the two "ab" tokens, to merge
+        assert_eq!(BasicTokenizer::plan_merges("abab", 300), vec![((97, 98), 256), ((256, 256), 257)]);
+    }
+}
+
+// Baseline contract of the crate: with every optional feature off, byte-level BPE is lossless
+#[cfg(test)]
+mod lossless_test {
+    use super::*;
+
+    const TRICKY_INPUTS: &[&str] = &[
+        "",
+        "a",
+        "Self driving is the future! 🙄",
+        "👨‍👩‍👧‍👦 🏳️‍🌈 🇮🇹",
+        "東京は日本の首都です。北京和上海",
+        "\0\u{1}\u{7}\u{1b}[0m\t\r\n\u{7f}",
+        "e\u{301} n\u{303} café",
+        "mixed: Ωmega ☃ 𝔘𝔫𝔦𝔠𝔬𝔡𝔢 \u{200b}zero width\u{feff}",
+        "    indented\n\n\n\ttabs\r\n",
+    ];
+
+    #[test]
+    fn test_roundtrip_byte_level() {
+        let text = std::fs::read_to_string("train.txt").expect("Failed to read file");
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train(&text, 400, false);
+
+        for &s in TRICKY_INPUTS {
+            assert_eq!(tokenizer.decode(&tokenizer.encode(s)).unwrap(), s, "round-trip failed for {s:?}");
+        }
     }
 }
@@ -0,0 +1,34 @@
+use std::process::Command;
+
+#[test]
+fn test_encode_missing_model() {
+    let path = std::env::temp_dir().join("toktokenizer_missing_model.txt");
+    let output = Command::new(env!("CARGO_BIN_EXE_toktokenizer"))
+        .args(["encode", path.to_str().unwrap(), "hello"])
+        .output()
+        .expect("Failed to run the CLI");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains(path.to_str().unwrap()));
+    assert!(stderr.contains("not found"));
+    assert!(!stderr.contains("Os {"));
+}
+
+#[test]
+fn test_train_missing_corpus() {
+    // Training reads train.txt from the working directory, run it from one without it
+    let dir = std::env::temp_dir().join("toktokenizer_missing_corpus");
+    std::fs::create_dir_all(&dir).expect("Failed to create the directory");
+    let output = Command::new(env!("CARGO_BIN_EXE_toktokenizer"))
+        .current_dir(&dir)
+        .output()
+        .expect("Failed to run the CLI");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains("train.txt"));
+    assert!(stderr.contains("not found"));
+    assert!(!stderr.contains("Os {"));
+    assert!(!dir.join("merges.txt").exists());
+}